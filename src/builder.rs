@@ -0,0 +1,151 @@
+//! Embedding the editor in another plugin.
+//!
+//! Implement EditorConfig to choose the editor's initial script, the components scripts see and
+//! any extra native functions, then use `RhaiEditor<MyConfig>` and `RhaiServer<MyConfig>` as the
+//! plugin's states:
+//!
+//! ```
+//! use cimvr_engine_interface::prelude::*;
+//! use rhai_plugin_test::{
+//!     script_components, CheckComponent, ComponentField, EditorBuilder, EditorConfig, RhaiEditor,
+//!     RhaiServer,
+//! };
+//! use serde::{Deserialize, Serialize};
+//!
+//! /// Seen by scripts as state.waves, without being able to change it
+//! #[derive(Component, Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+//! struct Wave {
+//!     height: f32,
+//! }
+//!
+//! impl CheckComponent for Wave {}
+//!
+//! struct Waves;
+//!
+//! impl EditorConfig for Waves {
+//!     fn editor() -> EditorBuilder {
+//!         let mut components = script_components();
+//!         components.push(ComponentField::serde::<Wave>("waves", Access::Read));
+//!         EditorBuilder::new()
+//!             .title("Waves")
+//!             .script("fn update() { for t in this.transforms.values() { t.pos.y = wave(now()); } }")
+//!             .components(components)
+//!             .register(|engine| {
+//!                 engine.register_fn("wave", |t: f32| t.sin());
+//!             })
+//!     }
+//! }
+//!
+//! type Client = RhaiEditor<Waves>;
+//! type Server = RhaiServer<Waves>;
+//! ```
+//!
+//! Then `make_app_state!(Client, Server);` defines the plugin's entry points with them.
+//! Depend on this crate with `default-features = false`, since its "standalone" feature defines
+//! the plugin's own entry points.
+use cimvr_engine_interface::println;
+use rhai::Engine;
+
+use crate::runtime::{describe_eval_error, script_components, ComponentField, ScriptRuntime};
+use crate::DEFAULT_SCRIPT;
+
+/// How RhaiEditor and RhaiServer are set up, for plugins embedding them
+pub trait EditorConfig: 'static {
+    /// Called once on each side as the plugin starts
+    fn editor() -> EditorBuilder {
+        EditorBuilder::new()
+    }
+}
+
+/// The setup of the standalone plugin
+pub struct DefaultEditor;
+
+impl EditorConfig for DefaultEditor {}
+
+/// Registers native functions with a script engine
+type RegisterFn = Box<dyn Fn(&mut Engine)>;
+
+/// Options for the editor, starting from those of the standalone plugin
+pub struct EditorBuilder {
+    pub(crate) title: String,
+    pub(crate) script: String,
+    pub(crate) components: Vec<ComponentField>,
+    functions: Vec<RegisterFn>,
+}
+
+impl Default for EditorBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EditorBuilder {
+    pub fn new() -> Self {
+        Self {
+            title: "Rhai".into(),
+            script: DEFAULT_SCRIPT.into(),
+            components: script_components(),
+            functions: vec![],
+        }
+    }
+
+    /// Name of the editor's window. The script's own window is named after it, e.g.
+    /// "Rhai Script".
+    pub fn title(mut self, title: &str) -> Self {
+        self.title = title.into();
+        self
+    }
+
+    /// Script the editor starts with, unless the server has a startup script
+    pub fn script(mut self, script: &str) -> Self {
+        self.script = script.into();
+        self
+    }
+
+    /// Components copied into `state`, in place of script_components(). To expose another
+    /// component type, append to script_components() as in the module's example. Only entities
+    /// with every one of the components are visible to scripts.
+    pub fn components(mut self, components: Vec<ComponentField>) -> Self {
+        self.components = components;
+        self
+    }
+
+    /// Register native functions, types and so on with the script engine, before any script
+    /// runs. This happens on the client and the server, since either may run the script.
+    pub fn register(mut self, register: impl Fn(&mut Engine) + 'static) -> Self {
+        self.functions.push(Box::new(register));
+        self
+    }
+
+    /// A runtime set up as configured, running `script`
+    pub(crate) fn runtime(&self, script: &str) -> ScriptRuntime {
+        let mut runtime = ScriptRuntime::with_components("", self.components.clone());
+        for register in &self.functions {
+            register(&mut runtime.engine);
+        }
+        if let Err(e) = runtime.compile(script) {
+            println!("Initial script failed: {}", describe_eval_error(e.error()));
+        }
+        runtime
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rhai::INT;
+
+    #[test]
+    fn registered_functions_reach_scripts() {
+        let builder = EditorBuilder::new().register(|engine| {
+            engine.register_fn("double", |x: INT| x * 2);
+        });
+
+        // Including the initial script's top-level statements
+        let mut runtime = builder.runtime("state.x = double(2);");
+        assert_eq!(
+            runtime.run_command("double(state.x)").unwrap().as_int(),
+            Ok(8)
+        );
+    }
+}
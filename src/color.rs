@@ -0,0 +1,119 @@
+//! Entity colors.
+//!
+//! `Render` has no color of its own; the default shader colors each vertex by its `uvw`. So an
+//! entity is tinted by pointing its `Render` at a copy of the cube mesh with every vertex set to
+//! one color. That only works for cubes, since any other mesh would be replaced by one.
+//!
+//! Colors are shown rounded to a palette of PALETTE_LEVELS³ colors, and a mesh is uploaded for
+//! each palette color as it's first used. Animating a color therefore uploads at most that many
+//! meshes, however long it runs.
+use std::collections::HashSet;
+
+use cimvr_common::render::{MeshHandle, UploadMesh};
+use cimvr_engine_interface::prelude::*;
+use rhai::{Array, Engine, EvalAltResult, FLOAT};
+
+use crate::math::to_float;
+use crate::runtime::{script_error, CUBE_HANDLE};
+
+/// Shades of each channel in the palette, evenly spaced from 0 to 1. Six gives the 216 "web safe"
+/// colors.
+const PALETTE_LEVELS: u8 = 6;
+
+/// RGBA, each from 0 to 1. Alpha is kept so colors round-trip, but the default shader ignores it.
+pub type Color = [f32; 4];
+
+pub fn register(engine: &mut Engine) {
+    engine.register_fn(
+        "color",
+        |r: FLOAT, g: FLOAT, b: FLOAT, a: FLOAT| -> Result<Array, Box<EvalAltResult>> {
+            let color = to_color(&vec![r.into(), g.into(), b.into(), a.into()])?;
+            Ok(from_color(color))
+        },
+    );
+}
+
+pub fn to_color(v: &Array) -> Result<Color, Box<EvalAltResult>> {
+    let [r, g, b, a] = v.as_slice() else {
+        return Err(script_error(
+            "TypeError",
+            format!("Expected a color of 4 numbers, found {} elements", v.len()),
+        ));
+    };
+
+    let color = [to_float(r)?, to_float(g)?, to_float(b)?, to_float(a)?];
+    if !color.iter().all(|c| (0.0..=1.0).contains(c)) {
+        return Err(script_error(
+            "ArgumentError",
+            format!(
+                "Color components must be between 0 and 1, found {:?}",
+                color
+            ),
+        ));
+    }
+    Ok(color)
+}
+
+pub fn from_color(color: Color) -> Array {
+    color.iter().map(|&c| c.into()).collect()
+}
+
+/// Cube meshes in each palette color, by the level of each channel
+pub struct TintedCubes {
+    /// Colors whose mesh has been uploaded so far
+    uploaded: HashSet<[u8; 3]>,
+    /// Handle of every palette color's mesh, uploaded or not. Other clients may have tinted an
+    /// entity with a color this one hasn't used.
+    handles: Vec<MeshHandle>,
+}
+
+impl Default for TintedCubes {
+    fn default() -> Self {
+        let levels = PALETTE_LEVELS as usize;
+        let handles = (0..levels.pow(3))
+            .map(|i| palette_handle([i / (levels * levels), i / levels % levels, i % levels]))
+            .collect();
+        Self {
+            uploaded: HashSet::new(),
+            handles,
+        }
+    }
+}
+
+impl TintedCubes {
+    /// Handle of the cube mesh in `color`, rounded to the palette, uploading it the first time
+    /// that palette color is used
+    pub fn handle(&mut self, io: &mut EngineIo, color: Color) -> MeshHandle {
+        let max = (PALETTE_LEVELS - 1) as f32;
+        let rgb = [color[0], color[1], color[2]].map(|c| (c * max).round() as u8);
+        let id = palette_handle(rgb.map(usize::from));
+
+        if self.uploaded.insert(rgb) {
+            let mut mesh = crate::cube();
+            let uvw = rgb.map(|c| c as f32 / max);
+            for vertex in &mut mesh.vertices {
+                vertex.uvw = uvw;
+            }
+            io.send(&UploadMesh { mesh, id });
+        }
+
+        id
+    }
+
+    /// Whether `mesh` is the plain cube or a tinted one, which are the only meshes that can be
+    /// colored
+    pub fn is_cube(&self, mesh: MeshHandle) -> bool {
+        mesh == CUBE_HANDLE || self.handles.contains(&mesh)
+    }
+}
+
+/// Handle of the cube mesh with each channel at the given palette level
+fn palette_handle(rgb: [usize; 3]) -> MeshHandle {
+    MeshHandle::new(&format!(
+        "{}/{}{}{}",
+        pkg_namespace!("RhaiCube"),
+        rgb[0],
+        rgb[1],
+        rgb[2]
+    ))
+}
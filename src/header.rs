@@ -0,0 +1,86 @@
+//! Metadata from the comment block at the top of a script, shown in the editor. For example:
+//!
+//! ```text
+//! // @name: Orbit
+//! // @author: Jane
+//! // Moves every entity in a circle
+//! fn update() { ... }
+//! ```
+//!
+//! Other comments in the block are ignored, as is anything after the first line of code.
+
+/// Name shown for scripts without an `@name`
+pub const DEFAULT_NAME: &str = "Untitled";
+
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ScriptHeader {
+    pub name: Option<String>,
+    pub author: Option<String>,
+}
+
+impl ScriptHeader {
+    pub fn parse(script: &str) -> Self {
+        let mut header = Self::default();
+        for line in script.lines().map(str::trim) {
+            if line.is_empty() {
+                continue;
+            }
+            let Some(comment) = line.strip_prefix("//") else {
+                break;
+            };
+            let Some((key, value)) = comment
+                .trim()
+                .strip_prefix('@')
+                .and_then(|c| c.split_once(':'))
+            else {
+                continue;
+            };
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            match key.trim() {
+                "name" => header.name = Some(value.to_string()),
+                "author" => header.author = Some(value.to_string()),
+                _ => (),
+            }
+        }
+        header
+    }
+
+    /// The name, followed by the author if there is one, e.g. "Orbit by Jane"
+    pub fn title(&self) -> String {
+        let name = self.name.as_deref().unwrap_or(DEFAULT_NAME);
+        match &self.author {
+            Some(author) => format!("{} by {}", name, author),
+            None => name.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_leading_comment_block() {
+        let header = ScriptHeader::parse(concat!(
+            "\n",
+            "// @name: Orbit\n",
+            "// Moves every entity in a circle\n",
+            "//@author:Jane\n",
+            "fn update() {}\n",
+            "// @name: Later\n",
+        ));
+        assert_eq!(header.name.as_deref(), Some("Orbit"));
+        assert_eq!(header.author.as_deref(), Some("Jane"));
+        assert_eq!(header.title(), "Orbit by Jane");
+    }
+
+    #[test]
+    fn missing_metadata_uses_the_default_name() {
+        let header = ScriptHeader::parse("// @name:\nfn update() {}");
+        assert_eq!(header, ScriptHeader::default());
+        assert_eq!(header.title(), DEFAULT_NAME);
+    }
+}
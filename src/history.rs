@@ -0,0 +1,87 @@
+//! Command line history, navigated with the Prev/Next buttons
+
+/// Maximum number of commands remembered
+const HISTORY_CAPACITY: usize = 100;
+
+#[derive(Default)]
+pub struct CommandHistory {
+    commands: Vec<String>,
+    /// Index of the command shown in the input; equal to `commands.len()` when past the end
+    cursor: usize,
+}
+
+impl CommandHistory {
+    /// Record a command which was run, and move the cursor back to the end
+    pub fn push(&mut self, command: &str) {
+        if self.commands.last().map(String::as_str) != Some(command) {
+            if self.commands.len() == HISTORY_CAPACITY {
+                self.commands.remove(0);
+            }
+            self.commands.push(command.to_string());
+        }
+        self.cursor = self.commands.len();
+    }
+
+    /// Step back to an older command, if there is one
+    pub fn prev(&mut self) -> Option<&str> {
+        if self.cursor == 0 {
+            return None;
+        }
+        self.cursor -= 1;
+        self.commands.get(self.cursor).map(String::as_str)
+    }
+
+    /// Step forward to a newer command. Stepping past the newest gives an empty command line.
+    pub fn next(&mut self) -> Option<&str> {
+        if self.cursor >= self.commands.len() {
+            return None;
+        }
+        self.cursor += 1;
+        Some(self.commands.get(self.cursor).map_or("", String::as_str))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_through_commands() {
+        let mut history = CommandHistory::default();
+        assert_eq!(history.prev(), None);
+
+        history.push("a");
+        history.push("b");
+        assert_eq!(history.prev(), Some("b"));
+        assert_eq!(history.prev(), Some("a"));
+        assert_eq!(history.prev(), None);
+        assert_eq!(history.next(), Some("b"));
+        assert_eq!(history.next(), Some(""));
+        assert_eq!(history.next(), None);
+    }
+
+    #[test]
+    fn push_skips_repeats_and_resets_cursor() {
+        let mut history = CommandHistory::default();
+        history.push("a");
+        history.push("a");
+        history.prev();
+        history.push("b");
+        assert_eq!(history.prev(), Some("b"));
+        assert_eq!(history.prev(), Some("a"));
+        assert_eq!(history.prev(), None);
+    }
+
+    #[test]
+    fn drops_oldest_past_capacity() {
+        let mut history = CommandHistory::default();
+        for i in 0..=HISTORY_CAPACITY {
+            history.push(&i.to_string());
+        }
+        let mut oldest = None;
+        while let Some(command) = history.prev() {
+            oldest = Some(command.to_string());
+        }
+        assert_eq!(oldest.as_deref(), Some("1"));
+    }
+}
@@ -1,155 +1,1030 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::marker::PhantomData;
 
 // Written by new.py, with love
-use cimvr_engine_interface::{dbg, make_app_state, prelude::*, println};
+use cimvr_engine_interface::{dbg, prelude::*, println};
 
 use cimvr_common::{
-    render::Render,
+    desktop::{ElementState, InputEvent, KeyCode, KeyboardEvent},
+    render::{Mesh, UploadMesh, Vertex},
     ui::{Schema, State, UiHandle, UiStateHelper, UiUpdate},
-    Transform,
+    FrameTime, Transform,
 };
-use rhai::{Dynamic, AST};
+use rhai::{format_map_as_json, Dynamic, EvalAltResult};
 
-// All state associated with client-side behaviour
-struct ClientState {
+mod builder;
+mod color;
+mod header;
+mod history;
+mod math;
+mod messages;
+mod profile;
+mod random;
+mod runtime;
+mod transform;
+mod undo;
+
+use header::ScriptHeader;
+use history::CommandHistory;
+use messages::*;
+use runtime::{error_line, push_line, ParkedScript, CUBE_HANDLE};
+use undo::TransformUndo;
+
+// The library's public surface, for plugins embedding the editor. See the builder module.
+pub use builder::{DefaultEditor, EditorBuilder, EditorConfig};
+pub use messages::StartupScript;
+pub use rhai;
+pub use runtime::{
+    components_query, describe_eval_error, script_components, transforms_query, CheckComponent,
+    CompileError, ComponentField, ScriptError, ScriptRuntime,
+};
+
+/// The standalone plugin's states
+#[cfg(feature = "standalone")]
+type ClientState = RhaiEditor;
+#[cfg(feature = "standalone")]
+type ServerState = RhaiServer;
+
+// All state associated with client-side behaviour: the editor, and the script it runs
+pub struct RhaiEditor<C: EditorConfig = DefaultEditor> {
     ui: UiStateHelper,
-    engine: rhai::Engine,
-    scope: rhai::Scope<'static>,
+    runtime: ScriptRuntime,
     widget: UiHandle,
-    script: String,
+    /// Window holding the script's TextBox
+    script_widget: UiHandle,
     response_text: String,
-    command: Option<String>,
+    /// Accumulated output: printed text, commands, and every status message with its time
+    output_text: String,
+    /// Values passed to watch() during the last frame
+    watches: BTreeMap<String, String>,
+    /// Commands from the command line waiting to run, oldest first. Every one queued since the
+    /// last frame runs, so quick clicks aren't lost.
+    commands: VecDeque<String>,
+    /// Command re-run every frame while "Continuous" is checked
+    continuous_command: Option<String>,
+    history: CommandHistory,
+    /// Whether update() is skipped each frame. Commands still run.
+    paused: bool,
+    /// Run update() once on the next frame despite being paused
+    step: bool,
+    /// Run update_entity() for each selected entity instead of update()
+    per_entity: bool,
+    /// Run update() in steps of FIXED_DT, as many as fit in the time elapsed
+    fixed_step: bool,
+    /// Time not yet used up by fixed steps
+    accumulator: f32,
+    /// Run commands (including key bindings) without keeping their changes to the ECS
+    dry_run: bool,
+    /// Have the server run the script instead, so its changes are authoritative and synced to
+    /// every client. Commands still run here.
+    run_on_server: bool,
+    /// The script last sent to the server in Run on Server mode, or None outside it
+    server_script: Option<String>,
+    config: PhantomData<fn() -> C>,
+    /// Editor widgets for state.ui, as of the last UI update
+    script_ui: rhai::Map,
+    /// Whether Ctrl is held, tracked from keyboard modifier events
+    ctrl_held: bool,
+    /// Run commands bound with bind_key(). Off by default, since keys typed into the editor would
+    /// trigger them too.
+    key_bindings: bool,
+    /// Keys pressed since the last frame, for running commands bound with bind_key()
+    pressed_keys: Vec<String>,
+    /// Earlier transforms of the scene, to step back to
+    undo: TransformUndo,
+    /// Restore the latest undo snapshot on the next frame
+    undo_requested: bool,
+    /// Name of the script in the editor, which is the only one whose update() runs
+    active_script: String,
+    /// Every other script, with its scope
+    scripts: HashMap<String, ParkedScript>,
+    /// Edited script waiting for typing to settle before it's compiled
+    pending_script: Option<String>,
+    /// Text last put in the script box or edited there, whether or not it compiled, so a script
+    /// which doesn't parse isn't compiled again until it's edited
+    editor_script: String,
+    /// Frames without further edits before the pending script is compiled
+    frames_until_compile: u32,
+    /// Whether the UI state has been reported as not matching the schema, so the warning isn't
+    /// repeated every frame until it does again
+    ui_shape_warned: bool,
+    /// Frames until `state` is next sent to the server. None until the server has replied to
+    /// StateSnapshotLoad, so a fresh state can't replace the one being restored.
+    frames_until_snapshot: Option<u32>,
+    /// The last StateSnapshot sent, so an unchanged state isn't sent again
+    last_snapshot: String,
+    /// Latest message from each source of recurring errors, so each is only logged when it
+    /// changes, even while other messages come and go
+    recurring: HashMap<Recurring, String>,
+}
+
+/// Things checked every frame, whose errors would otherwise flood the output
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Recurring {
+    SharedState,
+    Update,
+    ContinuousCommand,
+    CommandSyntax,
+    WriteBack,
 }
 
-const BUILTIN_SCRIPT: &str = include_str!("builtins.rhai");
-const DEFAULT_SCRIPT: &str = include_str!("default.rhai");
+/// Frames the script must go unedited before it's recompiled, so large scripts don't hitch the
+/// UI on every keystroke
+const COMPILE_DELAY_FRAMES: u32 = 15;
 
-impl UserState for ClientState {
-    // Implement a constructor
-    fn new(io: &mut EngineIo, sched: &mut EngineSchedule<Self>) -> Self {
-        let mut rhai_engine = rhai::Engine::new();
-        rhai_engine.on_print(|s: &str| println!("{}", s));
+/// How often `state` is sent to the server to be restored after a reload, in frames
+const SNAPSHOT_FRAMES: u32 = 60;
 
-        let mut ui = UiStateHelper::new();
+const COMPILING_TEXT: &str = "Compiling...";
 
-        // Create chat "window"
-        let schema = vec![
+/// Name of the script the editor starts with
+const MAIN_SCRIPT: &str = "main";
+
+/// Bytes of output kept in the output box; the oldest lines are dropped past this
+const OUTPUT_CAPACITY: usize = 8192;
+
+/// Characters of a command's return value shown in the UI. Longer ones, like entity_ids() in a
+/// big scene, would bloat the status line and output.
+const MAX_RETURN_CHARS: usize = 2000;
+
+/// Seconds per update() in Fixed Step mode
+const FIXED_DT: f32 = 1.0 / 60.0;
+
+/// Most update() calls per frame in Fixed Step mode
+const MAX_FIXED_STEPS: u32 = 4;
+
+/// Functions listed while profiling, starting with the most expensive
+const PROFILE_LINES: usize = 5;
+
+/// Position of the script's TextBox in its window. It has a window to itself, since the UI
+/// schema takes no size hints and longer scripts are cramped among the editor's controls.
+const IDX_SCRIPT: usize = 0;
+
+// Position of each widget in the editor window's schema and state
+const IDX_INPUT: usize = 0;
+const IDX_RUN: usize = 1;
+const IDX_CONTINUOUS: usize = 2;
+const IDX_LABEL: usize = 3;
+const IDX_OUTPUT: usize = 4;
+const IDX_SAVE: usize = 5;
+const IDX_LOAD: usize = 6;
+const IDX_CLEAR: usize = 7;
+const IDX_PREV: usize = 8;
+const IDX_NEXT: usize = 9;
+const IDX_SAVE_SERVER: usize = 10;
+const IDX_RESET: usize = 11;
+const IDX_SCENE_JSON: usize = 12;
+const IDX_IMPORT_SCENE: usize = 13;
+const IDX_PAUSE: usize = 14;
+const IDX_STEP: usize = 15;
+const IDX_SCRIPT_NAME: usize = 16;
+const IDX_NEW_SCRIPT: usize = 17;
+const IDX_SWITCH_SCRIPT: usize = 18;
+const IDX_DELETE_SCRIPT: usize = 19;
+const IDX_UNDO: usize = 20;
+const IDX_PROFILE: usize = 21;
+const IDX_PER_ENTITY: usize = 22;
+const IDX_FIXED_STEP: usize = 23;
+const IDX_DRY_RUN: usize = 24;
+const IDX_RUN_ON_SERVER: usize = 25;
+const IDX_KEY_BINDINGS: usize = 26;
+/// One button per entry of EXAMPLES, starting here
+const IDX_EXAMPLES: usize = 27;
+const EDITOR_WIDGET_COUNT: usize = IDX_EXAMPLES + EXAMPLES.len();
+
+/// Editor widgets scripts can see in state.ui, by name. Buttons are true on the frame they're
+/// clicked, checkboxes while checked, and text inputs hold their text.
+const SCRIPT_UI: [(&str, usize); 8] = [
+    ("command", IDX_INPUT),
+    ("run", IDX_RUN),
+    ("continuous", IDX_CONTINUOUS),
+    ("pause", IDX_PAUSE),
+    ("step", IDX_STEP),
+    ("undo", IDX_UNDO),
+    ("profile", IDX_PROFILE),
+    ("per_entity", IDX_PER_ENTITY),
+];
+
+/// Schema and initial state of the editor window. Widgets are listed alongside the index
+/// constant used to access them, and the order is checked so the two can't drift apart.
+fn editor_widgets() -> (Vec<Schema>, Vec<State>) {
+    let widgets = vec![
+        (
+            IDX_INPUT,
             Schema::TextInput,
+            State::TextInput {
+                text: "state.run_me()".into(),
+            },
+        ),
+        (
+            IDX_RUN,
             Schema::Button { text: "Run".into() },
+            State::Button { clicked: false },
+        ),
+        (
+            IDX_CONTINUOUS,
             Schema::CheckBox {
                 text: "Continuous".into(),
             },
-            Schema::Label,
+            State::CheckBox { checked: false },
+        ),
+        (IDX_LABEL, Schema::Label, State::Label { text: "".into() }),
+        (
+            IDX_OUTPUT,
             Schema::TextBox,
-        ];
-        let state = vec![
+            State::TextBox { text: "".into() },
+        ),
+        (
+            IDX_SAVE,
+            Schema::Button {
+                text: "Save".into(),
+            },
+            State::Button { clicked: false },
+        ),
+        (
+            IDX_LOAD,
+            Schema::Button {
+                text: "Load".into(),
+            },
+            State::Button { clicked: false },
+        ),
+        (
+            IDX_CLEAR,
+            Schema::Button {
+                text: "Clear".into(),
+            },
+            State::Button { clicked: false },
+        ),
+        (
+            IDX_PREV,
+            Schema::Button {
+                text: "Prev".into(),
+            },
+            State::Button { clicked: false },
+        ),
+        (
+            IDX_NEXT,
+            Schema::Button {
+                text: "Next".into(),
+            },
+            State::Button { clicked: false },
+        ),
+        (
+            IDX_SAVE_SERVER,
+            Schema::Button {
+                text: "Save to Server".into(),
+            },
+            State::Button { clicked: false },
+        ),
+        (
+            IDX_RESET,
+            Schema::Button {
+                text: "Reset State".into(),
+            },
+            State::Button { clicked: false },
+        ),
+        // Scene JSON to import, as written by transforms_to_json()
+        (
+            IDX_SCENE_JSON,
+            Schema::TextInput,
+            State::TextInput { text: "".into() },
+        ),
+        (
+            IDX_IMPORT_SCENE,
+            Schema::Button {
+                text: "Import Scene".into(),
+            },
+            State::Button { clicked: false },
+        ),
+        (
+            IDX_PAUSE,
+            Schema::CheckBox {
+                text: "Pause".into(),
+            },
+            State::CheckBox { checked: false },
+        ),
+        (
+            IDX_STEP,
+            Schema::Button {
+                text: "Step".into(),
+            },
+            State::Button { clicked: false },
+        ),
+        (
+            IDX_SCRIPT_NAME,
+            Schema::TextInput,
             State::TextInput {
-                text: "state.run_me()".into(),
+                text: MAIN_SCRIPT.into(),
+            },
+        ),
+        (
+            IDX_NEW_SCRIPT,
+            Schema::Button {
+                text: "New Script".into(),
+            },
+            State::Button { clicked: false },
+        ),
+        (
+            IDX_SWITCH_SCRIPT,
+            Schema::Button {
+                text: "Switch Script".into(),
+            },
+            State::Button { clicked: false },
+        ),
+        (
+            IDX_DELETE_SCRIPT,
+            Schema::Button {
+                text: "Delete Script".into(),
             },
             State::Button { clicked: false },
+        ),
+        (
+            IDX_UNDO,
+            Schema::Button {
+                text: "Undo".into(),
+            },
+            State::Button { clicked: false },
+        ),
+        (
+            IDX_PROFILE,
+            Schema::CheckBox {
+                text: "Profile".into(),
+            },
+            State::CheckBox { checked: false },
+        ),
+        (
+            IDX_PER_ENTITY,
+            Schema::CheckBox {
+                text: "Per Entity".into(),
+            },
+            State::CheckBox { checked: false },
+        ),
+        (
+            IDX_FIXED_STEP,
+            Schema::CheckBox {
+                text: "Fixed Step".into(),
+            },
+            State::CheckBox { checked: false },
+        ),
+        (
+            IDX_DRY_RUN,
+            Schema::CheckBox {
+                text: "Dry Run".into(),
+            },
+            State::CheckBox { checked: false },
+        ),
+        (
+            IDX_RUN_ON_SERVER,
+            Schema::CheckBox {
+                text: "Run on Server".into(),
+            },
             State::CheckBox { checked: false },
-            State::Label { text: "".into() },
-            State::TextBox {
-                text: DEFAULT_SCRIPT.into(),
+        ),
+        (
+            IDX_KEY_BINDINGS,
+            Schema::CheckBox {
+                text: "Key Bindings".into(),
             },
-        ];
-        let widget = ui.add(io, "Rhai", schema, state);
+            State::CheckBox { checked: false },
+        ),
+    ];
+
+    let examples = EXAMPLES.iter().enumerate().map(|(i, (name, _))| {
+        (
+            IDX_EXAMPLES + i,
+            Schema::Button {
+                text: format!("Example: {}", name),
+            },
+            State::Button { clicked: false },
+        )
+    });
+
+    widgets
+        .into_iter()
+        .chain(examples)
+        .enumerate()
+        .map(|(position, (idx, schema, state))| {
+            assert_eq!(position, idx, "Editor widget listed out of order");
+            (schema, state)
+        })
+        .unzip()
+}
+
+/// The widgets in SCRIPT_UI, for state.ui
+fn script_ui(ui_state: &[State]) -> rhai::Map {
+    SCRIPT_UI
+        .iter()
+        .filter_map(|&(name, idx)| {
+            let value: Dynamic = match &ui_state[idx] {
+                State::Button { clicked } => (*clicked).into(),
+                State::CheckBox { checked } => (*checked).into(),
+                State::TextInput { text } => text.clone().into(),
+                _ => return None,
+            };
+            Some((name.into(), value))
+        })
+        .collect()
+}
+
+/// Whether the button at `idx` was clicked
+fn clicked(ui_state: &[State], idx: usize) -> bool {
+    ui_state[idx] == State::Button { clicked: true }
+}
+
+/// Whether the checkbox at `idx` is checked
+fn checked(ui_state: &[State], idx: usize) -> bool {
+    ui_state[idx] == State::CheckBox { checked: true }
+}
+
+/// Text contents of the text input, text box or label at `idx`
+fn text(ui_state: &[State], idx: usize) -> &str {
+    match &ui_state[idx] {
+        State::TextInput { text } | State::TextBox { text } | State::Label { text } => text,
+        _ => panic!("Widget {} has no text", idx),
+    }
+}
+
+pub(crate) const DEFAULT_SCRIPT: &str = include_str!("default.rhai");
+
+/// Sample scripts which can be loaded into the editor, by name
+const EXAMPLES: [(&str, &str); 3] = [
+    ("Spinner", include_str!("examples/spinner.rhai")),
+    ("Orbit", include_str!("examples/orbit.rhai")),
+    ("Random Walk", include_str!("examples/random_walk.rhai")),
+];
+
+impl<C: EditorConfig> UserState for RhaiEditor<C> {
+    // Implement a constructor
+    fn new(io: &mut EngineIo, sched: &mut EngineSchedule<Self>) -> Self {
+        Self::with_builder(io, sched, C::editor())
+    }
+}
+
+impl<C: EditorConfig> RhaiEditor<C> {
+    /// Set up the editor as `builder` describes. new() does this with the config's builder.
+    pub fn with_builder(
+        io: &mut EngineIo,
+        sched: &mut EngineSchedule<Self>,
+        builder: EditorBuilder,
+    ) -> Self {
+        io.send(&UploadMesh {
+            mesh: cube(),
+            id: CUBE_HANDLE,
+        });
+
+        // Replaces the builder's script if the server was sent a StartupScript
+        io.send(&ScriptLoad {
+            slot: STARTUP_SLOT.into(),
+        });
+
+        let mut ui = UiStateHelper::new();
+
+        // Create chat "window"
+        let (schema, state) = editor_widgets();
+        let widget = ui.add(io, &builder.title, schema, state);
+        let script_widget = ui.add(
+            io,
+            &format!("{} Script", builder.title),
+            vec![Schema::TextBox],
+            vec![State::TextBox {
+                text: builder.script.clone(),
+            }],
+        );
 
         sched
             .add_system(Self::ui_update)
             .subscribe::<UiUpdate>()
+            .subscribe::<ScriptLoaded>()
+            .subscribe::<StateSnapshotLoaded>()
+            .subscribe::<InputEvent>()
             .build();
 
         sched
             .add_system(Self::transform_editor)
-            .query(
-                "Transforms",
-                Query::new()
-                    .intersect::<Transform>(Access::Write)
-                    .intersect::<Render>(Access::Read),
-            )
+            .subscribe::<FrameTime>()
+            .subscribe::<SharedStateSync>()
+            .subscribe::<ScriptMessage>()
+            .query("Transforms", components_query(&builder.components))
             .build();
 
-        let rhai_scope = rhai::Scope::new();
-
         Self {
-            command: None,
-            engine: rhai_engine,
-            scope: rhai_scope,
+            commands: VecDeque::new(),
+            continuous_command: None,
+            runtime: builder.runtime(&builder.script),
             widget,
+            script_widget,
             ui,
-            script: DEFAULT_SCRIPT.to_string(),
             response_text: "".into(),
+            output_text: String::new(),
+            watches: BTreeMap::new(),
+            history: CommandHistory::default(),
+            paused: false,
+            step: false,
+            per_entity: false,
+            fixed_step: false,
+            accumulator: 0.0,
+            dry_run: false,
+            run_on_server: false,
+            server_script: None,
+            config: PhantomData,
+            script_ui: rhai::Map::new(),
+            ctrl_held: false,
+            key_bindings: false,
+            pressed_keys: vec![],
+            undo: TransformUndo::default(),
+            undo_requested: false,
+            active_script: MAIN_SCRIPT.into(),
+            scripts: HashMap::new(),
+            pending_script: None,
+            editor_script: builder.script.clone(),
+            frames_until_compile: 0,
+            ui_shape_warned: false,
+            frames_until_snapshot: None,
+            last_snapshot: String::new(),
+            recurring: HashMap::new(),
         }
     }
-}
 
-impl ClientState {
-    fn run_command(&mut self, command: &str) -> Result<Dynamic, String> {
-        // Run update() function in script
-        //println!("{}", self.scope);
-        let script = format!("\n{}\n{}\n{}", self.script, BUILTIN_SCRIPT, command);
-        let result = self
-            .engine
-            .eval_with_scope::<Dynamic>(&mut self.scope, &script);
+    fn run_command(&mut self, command: &str) -> Result<Dynamic, ScriptError> {
+        let result = if self.dry_run {
+            self.runtime.dry_run_command(command)
+        } else {
+            self.runtime.run_command(command)
+        };
+        result.map_err(|e| {
+            // Commands are one line, but errors inside the script's functions are worth showing
+            let description = match *e {
+                EvalAltResult::ErrorInFunctionCall(..) => self.describe_script_error(&e),
+                _ => describe_eval_error(&e),
+            };
+            ScriptError::new(&e, description)
+        })
+    }
+
+    /// Show `text` in the status line, and add it to the output along with the time, so earlier
+    /// messages can still be read. The output is capped at OUTPUT_CAPACITY like everything else
+    /// in it, dropping the oldest lines first.
+    fn set_status(&mut self, text: impl Into<String>) {
+        self.response_text = text.into();
+        let entry = format!("[{:.1}s] {}", self.runtime.time(), self.response_text);
+        push_line(&mut self.output_text, &entry, OUTPUT_CAPACITY);
+    }
 
-        match result {
-            Err(e) => {
-                self.response_text = format!("Error running {}: {:#}", command, e);
-                Err(e.to_string())
-            },
-            Ok(dy) => Ok(dy),
+    /// Like set_status(), but only logged if it isn't the last message from `source`, for errors
+    /// which recur every frame
+    fn set_status_once(&mut self, source: Recurring, text: String) {
+        if self.recurring.get(&source) == Some(&text) {
+            self.response_text = text;
+        } else {
+            self.recurring.insert(source, text.clone());
+            self.set_status(text);
         }
     }
 
-    fn transform_editor(&mut self, _io: &mut EngineIo, query: &mut QueryResult) {
-        // The variable "State" will always be available
-        if self.scope.get("state").is_none() {
-            self.scope.push("state", rhai::Map::new());
+    /// Forget the last message from `source` once it succeeds, so the next error is logged again
+    /// even if it's the same one
+    fn clear_status_once(&mut self, source: Recurring) {
+        self.recurring.remove(&source);
+    }
+
+    /// Run the script for one frame. Everything runs against the same `state`, in this order:
+    ///
+    /// 1. Components are copied into `state`
+    /// 2. init(), on the first frame after the script compiles
+    /// 3. update(), or update_entity() for each selected entity in Per Entity mode, unless
+    ///    paused. In Fixed Step mode this happens as many times as fit in the frame, maybe none.
+    /// 4. Jobs started with start_job(), unless paused
+    /// 5. Commands from the command line queued since the last frame, oldest first
+    /// 6. The continuous command
+    /// 7. Commands bound to keys pressed since the last frame, then those scheduled with after()
+    ///    or after_frames() which are due
+    /// 8. `state` is copied back into the components
+    ///
+    /// So each step sees what the steps before it wrote to `state.transforms` this frame. The
+    /// `transforms` shorthand and lookups like get_transform() are copies taken at step 1, and
+    /// don't. In Dry Run mode, steps 5-7 are each rolled back once they've run, so they can't
+    /// change the ECS.
+    ///
+    /// In Run on Server mode, steps 2-4 happen on the server instead, and this client only sees
+    /// the results as the server's changes to the components are synced.
+    fn transform_editor(&mut self, io: &mut EngineIo, query: &mut QueryResult) {
+        self.compile_pending();
+
+        if let Some(sync) = io.inbox::<SharedStateSync>().last() {
+            match self.runtime.engine.parse_json(&sync.json, true) {
+                Ok(shared) => {
+                    self.runtime.set_shared(shared);
+                    self.clear_status_once(Recurring::SharedState);
+                }
+                Err(e) => self.set_status_once(
+                    Recurring::SharedState,
+                    format!("Bad shared state from server: {}", e),
+                ),
+            }
+        }
+
+        self.undo_transforms(query);
+
+        self.runtime.set_ui(self.script_ui.clone());
+        self.runtime.begin_frame(io, query);
+
+        // Clicks only show up in state.ui for one frame
+        let ui_state = self.ui.read(self.widget);
+        for (name, idx) in SCRIPT_UI {
+            if let Some(State::Button { .. }) = ui_state.get(idx) {
+                self.script_ui.insert(name.into(), false.into());
+            }
+        }
+
+        self.sync_server_script(io);
+
+        // One-time setup after each compile, even while paused
+        if !self.run_on_server {
+            if let Err(e) = self.runtime.run_init() {
+                let error = format!("Error running init(): {}", self.describe_script_error(&e));
+                self.set_status(error);
+            }
+        }
+
+        // Run update() function in script, if it has one
+        //println!("{}", self.runtime.scope);
+        let step = std::mem::take(&mut self.step);
+        let updates = if self.run_on_server {
+            0
+        } else if self.paused {
+            u32::from(step)
+        } else if self.fixed_step {
+            self.fixed_steps(self.runtime.dt())
+        } else {
+            1
+        };
+        if self.fixed_step {
+            self.runtime.set_dt(FIXED_DT);
+        } else {
+            self.accumulator = 0.0;
+        }
+
+        for _ in 0..updates {
+            let (name, result) = if self.per_entity {
+                ("update_entity", self.runtime.run_entity_updates())
+            } else {
+                ("update", self.runtime.run_update())
+            };
+            match result {
+                Ok(()) => self.clear_status_once(Recurring::Update),
+                Err(e) => {
+                    let error = format!(
+                        "Error running {}(): {}",
+                        name,
+                        self.describe_script_error(&e)
+                    );
+                    self.set_status_once(Recurring::Update, error);
+                    break;
+                }
+            }
+        }
+
+        // Jobs pause along with update(), and get one call a frame however many steps ran
+        if !self.run_on_server && (!self.paused || step) {
+            for e in self.runtime.run_jobs() {
+                let error = format!("Error running job: {}", self.describe_script_error(&e));
+                self.set_status(error);
+            }
+        }
+
+        // Run any command line commands, in the order they were queued
+        while let Some(command) = self.commands.pop_front() {
+            push_line(
+                &mut self.output_text,
+                &format!("> {}", command),
+                OUTPUT_CAPACITY,
+            );
+            let result = self.run_command(&command);
+            self.set_status(describe_command(&command, &result));
+        }
+
+        // The continuous command stays in place until the checkbox is unchecked. Its results
+        // only go to the status line, since it would fill the output within a few frames.
+        if let Some(command) = self.continuous_command.clone() {
+            // Errors are logged, but only when they start, not every frame they recur
+            match self.run_command(&command) {
+                Ok(d) => {
+                    self.response_text = describe_return(&d, false);
+                    self.clear_status_once(Recurring::ContinuousCommand);
+                }
+                Err(e) => self.set_status_once(
+                    Recurring::ContinuousCommand,
+                    format!("Error running {}: {}", command, e),
+                ),
+            }
         }
 
-        // Copy ECS data into rhai
-        let map: HashMap<String, Transform> = query
+        // Key bindings behave like one-shot commands, without being echoed to the output
+        for key in std::mem::take(&mut self.pressed_keys) {
+            if let Some(command) = self.runtime.key_binding(&key) {
+                let result = self.run_command(&command);
+                self.set_status(describe_command(&command, &result));
+            }
+        }
+
+        // So are commands scheduled with after() and after_frames()
+        for command in self.runtime.take_due_commands() {
+            let result = self.run_command(&command);
+            self.set_status(describe_command(&command, &result));
+        }
+
+        let errors = self.runtime.end_frame(io, query);
+        if errors.is_empty() {
+            self.clear_status_once(Recurring::WriteBack);
+        } else {
+            self.set_status_once(Recurring::WriteBack, errors.join("\n"));
+        }
+
+        let writes = self.runtime.take_shared_writes();
+        if !writes.is_empty() {
+            io.send(&SharedStateWrite {
+                json: format_map_as_json(&writes),
+            });
+        }
+
+        self.send_snapshot(io);
+
+        // Watches only last a frame, so ones the script stops updating disappear
+        self.watches = self.runtime.take_watches();
+
+        let prints = self.runtime.take_prints();
+        if !prints.is_empty() {
+            push_line(&mut self.output_text, prints.trim_end(), OUTPUT_CAPACITY);
+        }
+    }
+
+    /// Send the script's state to the server every SNAPSHOT_FRAMES frames, if it has changed
+    fn send_snapshot(&mut self, io: &mut EngineIo) {
+        let Some(frames) = self.frames_until_snapshot.as_mut() else {
+            return;
+        };
+        *frames = frames.saturating_sub(1);
+        if *frames > 0 {
+            return;
+        }
+        *frames = SNAPSHOT_FRAMES;
+
+        let (json, skipped) = self.runtime.snapshot_state();
+        if json != self.last_snapshot {
+            if !skipped.is_empty() {
+                println!(
+                    "Not kept across reloads: state.{}",
+                    skipped.join(", state.")
+                );
+            }
+            io.send(&StateSnapshot {
+                slot: self.active_script.clone(),
+                json: json.clone(),
+            });
+            self.last_snapshot = json;
+        }
+    }
+
+    /// In Run on Server mode, send the script to the server whenever it changes. Once the mode
+    /// is switched off, the server is given an empty script so it stops (unless another client
+    /// has uploaded one since), and this client takes over running the script again.
+    fn sync_server_script(&mut self, io: &mut EngineIo) {
+        if self.run_on_server {
+            if self.server_script.as_ref() != Some(&self.runtime.script) {
+                let script = self.runtime.script.clone();
+                io.send(&ServerScript {
+                    script: script.clone(),
+                });
+                self.server_script = Some(script);
+                self.set_status("Script sent to server, which now runs it");
+            }
+        } else if self.server_script.take().is_some() {
+            io.send(&ServerScript {
+                script: String::new(),
+            });
+            self.set_status("Script stopped on the server, and running here again");
+        }
+    }
+
+    /// How many fixed steps of update() to run this frame, `dt` seconds after the last. Time left
+    /// over carries into the next frame, except past MAX_FIXED_STEPS, where it's dropped so a
+    /// slow frame doesn't snowball into ever more updates.
+    fn fixed_steps(&mut self, dt: f32) -> u32 {
+        self.accumulator += dt;
+        let steps = (self.accumulator / FIXED_DT) as u32;
+        if steps > MAX_FIXED_STEPS {
+            self.accumulator = 0.0;
+            return MAX_FIXED_STEPS;
+        }
+        self.accumulator -= steps as f32 * FIXED_DT;
+        steps
+    }
+
+    /// Restore the latest undo snapshot if one was requested, otherwise record this frame's
+    /// transforms. Entities spawned since the snapshot are left where they are, and ones deleted
+    /// since are gone for good.
+    fn undo_transforms(&mut self, query: &mut QueryResult) {
+        if std::mem::take(&mut self.undo_requested) {
+            let Some(snapshot) = self.undo.undo() else {
+                self.set_status("Nothing to undo");
+                return;
+            };
+            let alive: HashSet<EntityId> = query.iter("Transforms").collect();
+            for (id, transform) in snapshot {
+                if alive.contains(&id) {
+                    query.write(id, &transform);
+                }
+            }
+            self.set_status("Undone");
+            return;
+        }
+
+        let current = query
             .iter("Transforms")
-            .map(|id @ EntityId(num)| (num.to_string(), query.read::<Transform>(id)))
+            .map(|id| (id, query.read::<Transform>(id)))
             .collect();
-        let transforms_rhai = rhai::serde::to_dynamic(&map).unwrap();
+        self.undo.record(current);
+    }
 
-        // TODO: Just how slow is this?
-        if let Some(mut state) = self.scope.remove::<rhai::Map>("state") {
-            state.insert("transforms".into(), transforms_rhai);
-            self.scope.set_value("state", state);
+    /// Compile the edited script once it has gone unchanged for long enough
+    fn compile_pending(&mut self) {
+        if self.pending_script.is_none() {
+            return;
         }
 
-        // Run update() function in script
-        //println!("{}", self.scope);
-        let _ = self.run_command("state.update();");
+        self.frames_until_compile = self.frames_until_compile.saturating_sub(1);
+        if self.frames_until_compile == 0 {
+            if let Some(script) = self.pending_script.take() {
+                self.compile_script(&script);
+            }
+        }
+    }
 
-        // Run any command line commands
-        if let Some(command) = self.command.take() {
-            if let Ok(d) = self.run_command(&command) {
-                self.response_text = format!("Returned: {}", d);
+    /// Compile the user's script, returning whether it compiled and its top-level statements ran.
+    /// If it doesn't parse the previous AST is kept, so update() keeps running.
+    fn compile_script(&mut self, text: &str) -> bool {
+        let result = self.runtime.compile(text);
+        if let Err(CompileError::Parse(e)) = &result {
+            // The script which failed isn't kept, so echo from the text given
+            self.set_status(format!(
+                "Script compile error: {}",
+                describe_error_in(text, e)
+            ));
+            return false;
+        }
+
+        // Errors from the old script no longer apply
+        if self.response_text.contains("Script compile error")
+            || self.response_text.starts_with("Error running")
+            || self.response_text == COMPILING_TEXT
+            || self.response_text.starts_with("Warning: ")
+        {
+            self.set_status("Compilation successful");
+        }
+        // Likely mistakes are only warned about, and the script still runs
+        let warnings = self.runtime.lint();
+        if !warnings.is_empty() {
+            self.set_status(format!("Warning: {}", warnings.join("\nWarning: ")));
+        }
+
+        // The script is in place either way, but may not have finished setting up
+        match result {
+            Err(e) => {
+                self.set_status(format!(
+                    "Error running the script's top-level statements: {}",
+                    self.describe_script_error(e.error())
+                ));
+                false
             }
+            Ok(()) => true,
         }
+    }
 
-        // Copy ECS data back into cimvr
-        if let Some(mut state) = self.scope.remove::<rhai::Map>("state") {
-            if let Some(transforms) = state.remove("transforms".into()) {
-                let ret_map: Result<HashMap<String, Transform>, _> =
-                    rhai::serde::from_dynamic(&transforms);
+    /// Replace the editor contents with `script` and compile it
+    fn replace_script(&mut self, io: &mut EngineIo, script: &str) -> bool {
+        self.show_script(io, script);
+        self.compile_script(script)
+    }
 
-                match ret_map {
-                    Err(e) => self.response_text = format!("Error: {}", e),
-                    Ok(ret_map) => {
-                        for (key, value) in ret_map {
-                            let ent = EntityId(key.parse().unwrap());
-                            query.write(ent, &value);
-                        }
-                    }
+    /// Replace the editor contents without compiling them
+    fn show_script(&mut self, io: &mut EngineIo, script: &str) {
+        // Any edits still waiting to compile are overwritten
+        self.pending_script = None;
+        self.editor_script = script.to_string();
+        self.ui.modify(io, self.script_widget, |ui_state| {
+            if let Some(state) = ui_state.get_mut(IDX_SCRIPT) {
+                *state = State::TextBox {
+                    text: script.to_string(),
+                };
+            }
+        });
+    }
+
+    /// Start a new, empty script called `name` and switch to it
+    fn new_script(&mut self, io: &mut EngineIo, name: &str) {
+        if name.is_empty() {
+            self.set_status("Scripts need a name");
+        } else if name == self.active_script || self.scripts.contains_key(name) {
+            self.set_status(format!("There's already a script called {}", name));
+        } else {
+            self.scripts
+                .insert(name.to_string(), ParkedScript::default());
+            self.switch_script(io, name);
+            if self.active_script == name {
+                self.set_status(format!("Created script {}", name));
+            }
+        }
+    }
+
+    /// Park the running script and run `name` instead, carrying on with its scope
+    fn switch_script(&mut self, io: &mut EngineIo, name: &str) {
+        if name == self.active_script {
+            self.set_status(format!("Already editing {}", name));
+            return;
+        }
+        let Some(mut parked) = self.scripts.remove(name) else {
+            self.set_status(format!("No script called {}", name));
+            return;
+        };
+
+        // Don't lose edits which haven't been compiled yet
+        if let Some(script) = self.pending_script.take() {
+            self.compile_script(&script);
+        }
+        // Only scripts which parse are kept, so switching away would drop the edits
+        if self.editor_script != self.runtime.script {
+            self.set_status("Fix the script's compile error before switching to another one");
+            self.scripts.insert(name.to_string(), parked);
+            return;
+        }
+
+        self.runtime.swap_script(&mut parked);
+        let previous = std::mem::replace(&mut self.active_script, name.to_string());
+        self.scripts.insert(previous, parked);
+        // Snapshots are kept per script, so this one's is sent even if it looks the same
+        self.last_snapshot.clear();
+
+        let script = self.runtime.script.clone();
+        self.show_script(io, &script);
+        self.set_status(format!("Switched to {}", name));
+    }
+
+    /// Delete a script other than the running one, along with its scope
+    fn delete_script(&mut self, name: &str) {
+        if name == self.active_script {
+            self.set_status("Switch to another script before deleting this one");
+        } else if self.scripts.remove(name).is_some() {
+            self.set_status(format!("Deleted script {}", name));
+        } else {
+            self.set_status(format!("No script called {}", name));
+        }
+    }
+
+    /// Describe an error in the running script, echoing the line it points at
+    fn describe_script_error(&self, e: &EvalAltResult) -> String {
+        describe_error_in(&self.runtime.script, e)
+    }
+
+    /// Parse a command without running it, so a typo is reported as such rather than as an
+    /// error from running it. Continuous mode checks every frame, so each error is only logged
+    /// once.
+    fn check_command(&mut self, command: &str) -> bool {
+        match self.runtime.engine.compile(command) {
+            Ok(_) => {
+                self.clear_status_once(Recurring::CommandSyntax);
+                true
+            }
+            Err(e) => {
+                let error = format!("Command syntax error: {}", describe_eval_error(&e.into()));
+                self.set_status_once(Recurring::CommandSyntax, error);
+                false
+            }
+        }
+    }
+
+    /// Whether Ctrl+Enter was pressed this frame. Other key presses are kept for bind_key() while
+    /// Key Bindings is checked. The UI doesn't tell us which widget has focus, so both fire
+    /// regardless of where the user is typing.
+    fn run_shortcut_pressed(&mut self, io: &mut EngineIo) -> bool {
+        let mut pressed = false;
+        for event in io.inbox::<InputEvent>() {
+            match event {
+                InputEvent::Keyboard(KeyboardEvent::Modifiers(modifiers)) => {
+                    self.ctrl_held = modifiers.ctrl;
                 }
+                // Plain Enter is left for bindings, so only Ctrl+Enter is taken as the shortcut
+                InputEvent::Keyboard(KeyboardEvent::Key {
+                    key: KeyCode::Return,
+                    state: ElementState::Pressed,
+                }) if self.ctrl_held => pressed = true,
+                InputEvent::Keyboard(KeyboardEvent::Key {
+                    key,
+                    state: ElementState::Pressed,
+                }) if self.key_bindings => self.pressed_keys.push(format!("{:?}", key)),
+                _ => (),
             }
-            self.scope.set_value("state", state);
         }
+        pressed
     }
 
     fn ui_update(&mut self, io: &mut EngineIo, _query: &mut QueryResult) {
@@ -157,52 +1032,664 @@ impl ClientState {
         self.ui.download(io);
 
         // Compile the script
-        let ui_state = self.ui.read(self.widget);
+        let ui_state = self.ui.read(self.widget).to_vec();
+        let script_state = self.ui.read(self.script_widget).to_vec();
+
+        // Every widget below is accessed by index, so wait for state matching the schema rather
+        // than panicking on state which is missing or stale
+        if ui_state.len() != EDITOR_WIDGET_COUNT || script_state.get(IDX_SCRIPT).is_none() {
+            if !self.ui_shape_warned {
+                println!(
+                    "Warning: skipping UI updates, expected {} editor widgets but found {}",
+                    EDITOR_WIDGET_COUNT,
+                    ui_state.len()
+                );
+                self.ui_shape_warned = true;
+            }
+            return;
+        }
+        self.ui_shape_warned = false;
 
         // Check for UI updates
         if io.inbox::<UiUpdate>().next().is_some() {
-            let State::TextBox { text } = &ui_state[4] else { panic!() };
-            let script_compile_result = self.engine.compile(text);
-
-            match script_compile_result {
-                Ok(_ast) => {
-                    self.script = text.clone();
-                    if self.response_text.contains("Script compile error") {
-                        self.response_text = format!("Compilation successful");
+            let script = text(&script_state, IDX_SCRIPT);
+
+            // UiUpdate also fires for button clicks, so only recompile when the text changed.
+            // Each edit restarts the delay; transform_editor() compiles once it runs out.
+            if script != self.editor_script {
+                self.editor_script = script.to_string();
+                self.pending_script = Some(script.to_string());
+                self.frames_until_compile = COMPILE_DELAY_FRAMES;
+                self.response_text = COMPILING_TEXT.into();
+            }
+        }
+
+        self.key_bindings = checked(&ui_state, IDX_KEY_BINDINGS);
+
+        // Set the command line, from either the Run button or Ctrl+Enter
+        let shortcut = self.run_shortcut_pressed(io);
+        if clicked(&ui_state, IDX_RUN) || shortcut {
+            let command = text(&ui_state, IDX_INPUT);
+            if self.check_command(command) {
+                self.commands.push_back(command.to_string());
+            }
+
+            // Only explicit runs are remembered, otherwise Continuous would flood the history.
+            // Commands with typos are too, so they can be recalled and fixed.
+            self.history.push(command);
+        }
+
+        self.script_ui = script_ui(&ui_state);
+
+        self.paused = checked(&ui_state, IDX_PAUSE);
+        self.per_entity = checked(&ui_state, IDX_PER_ENTITY);
+        self.fixed_step = checked(&ui_state, IDX_FIXED_STEP);
+        self.dry_run = checked(&ui_state, IDX_DRY_RUN);
+        self.run_on_server = checked(&ui_state, IDX_RUN_ON_SERVER);
+
+        // Counts start over each time this is checked
+        self.runtime
+            .profiler
+            .set_enabled(checked(&ui_state, IDX_PROFILE));
+
+        // Single-step while paused; repeated clicks before the next frame still make one step
+        if self.paused && clicked(&ui_state, IDX_STEP) {
+            self.step = true;
+        }
+
+        // Follow edits to the command line while running continuously, pausing while it's
+        // half typed
+        self.continuous_command = checked(&ui_state, IDX_CONTINUOUS)
+            .then(|| text(&ui_state, IDX_INPUT))
+            .filter(|command| self.check_command(command))
+            .map(str::to_string);
+
+        // Recall previous commands into the command line
+        let recalled = if clicked(&ui_state, IDX_PREV) {
+            self.history.prev()
+        } else if clicked(&ui_state, IDX_NEXT) {
+            self.history.next()
+        } else {
+            None
+        };
+
+        if let Some(command) = recalled.map(str::to_string) {
+            self.ui.modify(io, self.widget, |ui_state| {
+                ui_state[IDX_INPUT] = State::TextInput {
+                    text: command.clone(),
+                };
+            });
+        }
+
+        // Save the script text exactly as it appears in the editor
+        if clicked(&ui_state, IDX_SAVE) {
+            io.send(&ScriptSaved {
+                slot: DEFAULT_SLOT.into(),
+                script: text(&script_state, IDX_SCRIPT).to_string(),
+            });
+            self.set_status("Script saved");
+        }
+
+        // Run the script on the server as well, against the server's copy of the world
+        if clicked(&ui_state, IDX_SAVE_SERVER) {
+            io.send(&ServerScript {
+                script: text(&script_state, IDX_SCRIPT).to_string(),
+            });
+            self.set_status("Script sent to server");
+        }
+
+        // Ask the server for the saved script; the reply is handled below on a later frame
+        if clicked(&ui_state, IDX_LOAD) {
+            io.send(&ScriptLoad {
+                slot: DEFAULT_SLOT.into(),
+            });
+        }
+
+        // Replace the editor contents with a previously saved script
+        if let Some(loaded) = io.inbox::<ScriptLoaded>().last() {
+            // The state from before a reload is restored once the startup script has run, so
+            // its top-level statements don't overwrite it
+            if loaded.slot == STARTUP_SLOT {
+                io.send(&StateSnapshotLoad {
+                    slot: MAIN_SCRIPT.into(),
+                });
+            }
+            match loaded.script {
+                // Without a startup script, the default one stays
+                None if loaded.slot == STARTUP_SLOT => (),
+                None => self.set_status("No saved script"),
+                Some(script) => {
+                    if self.replace_script(io, &script) {
+                        self.set_status(if loaded.slot == STARTUP_SLOT {
+                            "Loaded startup script"
+                        } else {
+                            "Loaded saved script"
+                        });
                     }
                 }
-                Err(e) => self.response_text = format!("Script compile error: {:#}", e),
-            };
+            }
         }
 
-        // Set the command line
-        if ui_state[1] == (State::Button { clicked: true })
-            || ui_state[2] == (State::CheckBox { checked: true })
-        {
-            let State::TextInput { text } = &ui_state[0] else { panic!() };
-            //let cmd_compile_result = self.engine.compile_expression(text);
-            self.command = Some(text.clone());
+        if let Some(loaded) = io.inbox::<StateSnapshotLoaded>().last() {
+            // Dropped if another script was switched to while waiting, since it belongs to main
+            let json = loaded.json.filter(|_| loaded.slot == self.active_script);
+            if let Some(json) = json {
+                match self.runtime.restore_state(&json) {
+                    Ok(count) => self.set_status(format!("Restored {} entries of state", count)),
+                    Err(e) => self.set_status(format!(
+                        "Couldn't restore state: {}",
+                        describe_eval_error(&e)
+                    )),
+                }
+            }
+            self.frames_until_snapshot = Some(SNAPSHOT_FRAMES);
+        }
+
+        // Manage named scripts; each keeps its own text and scope while another one runs
+        let name = text(&ui_state, IDX_SCRIPT_NAME).trim();
+        if clicked(&ui_state, IDX_NEW_SCRIPT) {
+            self.new_script(io, name);
+        }
+        if clicked(&ui_state, IDX_SWITCH_SCRIPT) {
+            self.switch_script(io, name);
+        }
+        if clicked(&ui_state, IDX_DELETE_SCRIPT) {
+            self.delete_script(name);
+        }
+
+        // Replace the editor contents with a bundled example
+        for (i, (name, script)) in EXAMPLES.iter().enumerate() {
+            if clicked(&ui_state, IDX_EXAMPLES + i) && self.replace_script(io, script) {
+                self.set_status(format!("Loaded example {}", name));
+            }
+        }
+
+        // Spawn a scene exported with transforms_to_json() and pasted in. The plugin runs as
+        // wasm on both sides, with no access to files, so scenes can't be read from disk.
+        if clicked(&ui_state, IDX_IMPORT_SCENE) {
+            let imported = self
+                .runtime
+                .import_scene(io, text(&ui_state, IDX_SCENE_JSON));
+            self.set_status(match imported {
+                Ok(ids) => format!("Imported {} entities", ids.len()),
+                Err(e) => format!("Error importing scene: {}", describe_eval_error(&e)),
+            });
+        }
+
+        // Recompiling keeps `state` so live coding is seamless; this starts it over explicitly
+        if clicked(&ui_state, IDX_RESET) {
+            self.runtime.reset_state();
+            self.set_status("State reset");
+        }
+
+        // Takes effect next frame, since only transform_editor() can write transforms. Pause
+        // first, or update() carries on from the restored transforms straight away.
+        if clicked(&ui_state, IDX_UNDO) {
+            self.undo_requested = true;
+        }
+
+        // Clear the status and output, including the script log
+        if clicked(&ui_state, IDX_CLEAR) {
+            self.response_text.clear();
+            self.output_text.clear();
+            self.runtime.log.lock().unwrap().clear();
+        }
+
+        // Set the response text after the script's name, title and length, followed by any
+        // script log messages. The header only covers the first few lines, so it's cheap to
+        // parse again each time.
+        let script = text(&script_state, IDX_SCRIPT);
+        let mut label = format!(
+            "Script {} ({}): {} lines\n",
+            self.active_script,
+            ScriptHeader::parse(script).title(),
+            script.lines().count()
+        );
+        if !self.scripts.is_empty() {
+            let mut others: Vec<&str> = self.scripts.keys().map(String::as_str).collect();
+            others.sort_unstable();
+            label.push_str(&format!("Other scripts: {}\n", others.join(", ")));
+        }
+        if self.paused {
+            label.push_str("Paused\n");
+        }
+        label.push_str(&format!(
+            "Perf: update() took {} operations\n",
+            self.runtime.update_operations()
+        ));
+        if self.runtime.job_count() > 0 {
+            label.push_str(&format!("Jobs running: {}\n", self.runtime.job_count()));
+        }
+        let profile = self.runtime.profiler.report();
+        if !profile.is_empty() {
+            label.push_str("Profile (steps, calls):\n");
+            for (name, function) in profile.iter().take(PROFILE_LINES) {
+                label.push_str(&format!(
+                    "  {}(): {}, {}\n",
+                    name, function.steps, function.calls
+                ));
+            }
+        }
+        label.push_str(&self.response_text);
+        if !self.watches.is_empty() {
+            label.push_str("\nWatches:");
+            for (name, value) in &self.watches {
+                label.push_str(&format!("\n  {} = {}", name, value));
+            }
+        }
+        for line in self.runtime.log.lock().unwrap().iter() {
+            label.push('\n');
+            label.push_str(line);
         }
 
-        // Set the response text
         self.ui.modify(io, self.widget, |ui_state| {
-            ui_state[3] = State::Label {
-                text: self.response_text.clone(),
+            ui_state[IDX_LABEL] = State::Label {
+                text: label.clone(),
+            };
+            // Overwritten every time, which keeps the output read-only
+            ui_state[IDX_OUTPUT] = State::TextBox {
+                text: self.output_text.clone(),
             };
         });
     }
 }
 
+/// Describe a command's result along with its type, e.g. "Returned (i64): 42". Values longer
+/// than MAX_RETURN_CHARS are cut short, and printed in full to the host log instead if
+/// `log_full` is set. That's only for one-shot commands, since continuous ones would flood it.
+fn describe_return(value: &Dynamic, log_full: bool) -> String {
+    if value.is_unit() {
+        return "Returned: ()".into();
+    }
+
+    let text = value.to_string();
+    match text.char_indices().nth(MAX_RETURN_CHARS) {
+        Some((end, _)) => {
+            if log_full {
+                println!("Returned ({}): {}", value.type_name(), text);
+            }
+            format!(
+                "Returned ({}): {}…(truncated)",
+                value.type_name(),
+                &text[..end]
+            )
+        }
+        None => format!("Returned ({}): {}", value.type_name(), text),
+    }
+}
+
+/// Describe the outcome of running `command`, for the status line
+fn describe_command(command: &str, result: &Result<Dynamic, ScriptError>) -> String {
+    match result {
+        Ok(value) => describe_return(value, true),
+        Err(e) => format!("Error running {}: {}", command, e),
+    }
+}
+
+/// Describe an error in `script`, echoing the line it points at
+fn describe_error_in(script: &str, e: &EvalAltResult) -> String {
+    let description = describe_eval_error(e);
+    match error_line(e).and_then(|line| echo_line(script, line)) {
+        Some(echo) => format!("{}\n{}", description, echo),
+        None => description,
+    }
+}
+
+/// Echo a line of the script, e.g. ">> 12: state.update()". Positions can be off when the error
+/// came from somewhere else (like the builtins), so a line past the end gives None.
+fn echo_line(script: &str, line: usize) -> Option<String> {
+    let text = script.lines().nth(line.checked_sub(1)?)?;
+    Some(format!(">> {}: {}", line, text.trim()))
+}
+
+pub(crate) fn cube() -> Mesh {
+    let size = 0.25;
+
+    let vertices = vec![
+        Vertex::new([-size, -size, -size], [0.0, 1.0, 1.0]),
+        Vertex::new([size, -size, -size], [1.0, 0.0, 1.0]),
+        Vertex::new([size, size, -size], [1.0, 1.0, 0.0]),
+        Vertex::new([-size, size, -size], [0.0, 1.0, 1.0]),
+        Vertex::new([-size, -size, size], [1.0, 0.0, 1.0]),
+        Vertex::new([size, -size, size], [1.0, 1.0, 0.0]),
+        Vertex::new([size, size, size], [0.0, 1.0, 1.0]),
+        Vertex::new([-size, size, size], [1.0, 0.0, 1.0]),
+    ];
+
+    let indices = vec![
+        3, 1, 0, 2, 1, 3, 2, 5, 1, 6, 5, 2, 6, 4, 5, 7, 4, 6, 7, 0, 4, 3, 0, 7, 7, 2, 3, 6, 2, 7,
+        0, 1, 5, 0, 5, 4,
+    ];
+
+    Mesh { vertices, indices }
+}
+
 // All state associated with server-side behaviour
-struct ServerState;
+pub struct RhaiServer<C: EditorConfig = DefaultEditor> {
+    /// Latest script saved in each slot
+    saved_scripts: HashMap<String, String>,
+    /// Latest StateSnapshot from each client, by script name. Only kept in memory, so they're
+    /// gone if the server restarts.
+    state_snapshots: HashMap<(ClientId, String), String>,
+    /// Runs the script uploaded with "Save to Server"
+    runtime: ScriptRuntime,
+    /// Client which uploaded the running script, and so the only one which can stop it
+    script_owner: Option<ClientId>,
+    /// Last error reported by the server script, so it's only logged once
+    last_error: Option<String>,
+    /// State shared between every client's scripts and the server's
+    shared: rhai::Map,
+    config: PhantomData<fn() -> C>,
+}
 
-impl UserState for ServerState {
+impl<C: EditorConfig> UserState for RhaiServer<C> {
     // Implement a constructor
-    fn new(_io: &mut EngineIo, _sched: &mut EngineSchedule<Self>) -> Self {
-        Self
+    fn new(_io: &mut EngineIo, sched: &mut EngineSchedule<Self>) -> Self {
+        // Only the components and functions apply here; the server starts without a script
+        let builder = C::editor();
+
+        sched
+            .add_system(Self::script_storage)
+            .subscribe::<ScriptSaved>()
+            .subscribe::<ScriptLoad>()
+            .subscribe::<StartupScript>()
+            .subscribe::<StateSnapshot>()
+            .subscribe::<StateSnapshotLoad>()
+            .build();
+
+        sched
+            .add_system(Self::script_update)
+            .subscribe::<ServerScript>()
+            .subscribe::<FrameTime>()
+            .subscribe::<ScriptMessage>()
+            .query("Transforms", components_query(&builder.components))
+            .build();
+
+        sched
+            .add_system(Self::shared_state)
+            .subscribe::<SharedStateWrite>()
+            .build();
+
+        Self {
+            saved_scripts: HashMap::new(),
+            state_snapshots: HashMap::new(),
+            runtime: builder.runtime(""),
+            script_owner: None,
+            last_error: None,
+            shared: rhai::Map::new(),
+            config: PhantomData,
+        }
+    }
+}
+
+impl<C: EditorConfig> RhaiServer<C> {
+    /// Save and load scripts and state for clients. Scripts only ever come from clients: the
+    /// server runs as wasm too, without a filesystem, so it can't load them from files on disk
+    /// or watch those files for changes. Live edits go through the editor instead.
+    fn script_storage(&mut self, io: &mut EngineIo, _query: &mut QueryResult) {
+        for (_client, msg) in io.inbox_clients::<ScriptSaved>().collect::<Vec<_>>() {
+            self.saved_scripts.insert(msg.slot, msg.script);
+        }
+
+        for msg in io.inbox::<StartupScript>().collect::<Vec<_>>() {
+            set_startup_script(&mut self.saved_scripts, msg.script);
+        }
+
+        for (client, msg) in io.inbox_clients::<ScriptLoad>().collect::<Vec<_>>() {
+            // Every client asks for the startup script as it starts, so this is when clients
+            // joining late catch up on the shared state; otherwise they'd only see it once it
+            // next changes
+            if msg.slot == STARTUP_SLOT && !self.shared.is_empty() {
+                io.send_to_client(
+                    &SharedStateSync {
+                        json: format_map_as_json(&self.shared),
+                    },
+                    client,
+                );
+            }
+
+            let script = self.saved_scripts.get(&msg.slot).cloned();
+            io.send_to_client(
+                &ScriptLoaded {
+                    slot: msg.slot,
+                    script,
+                },
+                client,
+            );
+        }
+
+        for (client, msg) in io.inbox_clients::<StateSnapshot>().collect::<Vec<_>>() {
+            self.state_snapshots.insert((client, msg.slot), msg.json);
+        }
+
+        for (client, msg) in io.inbox_clients::<StateSnapshotLoad>().collect::<Vec<_>>() {
+            let json = self
+                .state_snapshots
+                .get(&(client, msg.slot.clone()))
+                .cloned();
+            io.send_to_client(
+                &StateSnapshotLoaded {
+                    slot: msg.slot,
+                    json,
+                },
+                client,
+            );
+        }
+    }
+
+    fn shared_state(&mut self, io: &mut EngineIo, _query: &mut QueryResult) {
+        for (_client, msg) in io.inbox_clients::<SharedStateWrite>().collect::<Vec<_>>() {
+            match self.runtime.engine.parse_json(&msg.json, true) {
+                Ok(writes) => self.merge_shared(io, writes),
+                Err(e) => println!("Bad shared state write: {}", e),
+            }
+        }
+    }
+
+    /// Apply writes to the shared state, with the last write to each key winning, and send the
+    /// result to every client
+    fn merge_shared(&mut self, io: &mut EngineIo, writes: rhai::Map) {
+        if writes.is_empty() {
+            return;
+        }
+
+        self.shared.extend(writes);
+        self.runtime.set_shared(self.shared.clone());
+        io.send(&SharedStateSync {
+            json: format_map_as_json(&self.shared),
+        });
+    }
+
+    fn script_update(&mut self, io: &mut EngineIo, query: &mut QueryResult) {
+        let uploads = io.inbox_clients::<ServerScript>();
+        if let Some(script) = newest_server_script(&mut self.script_owner, uploads) {
+            match self.runtime.compile(&script) {
+                Ok(()) => self.last_error = None,
+                Err(CompileError::Parse(e)) => self.report(format!(
+                    "Server script compile error: {}",
+                    describe_eval_error(&e)
+                )),
+                Err(CompileError::Run(e)) => self.report(format!(
+                    "Server script error in top-level statements: {}",
+                    describe_eval_error(&e)
+                )),
+            }
+        }
+
+        // Nothing runs until a client switches on Run on Server, or after it's switched off.
+        // Skipping the frame also saves reserving entities for spawns every frame.
+        if self.runtime.script.trim().is_empty() {
+            return;
+        }
+
+        self.runtime.begin_frame(io, query);
+
+        if let Err(e) = self.runtime.run_init() {
+            self.report(format!(
+                "Server script init() error: {}",
+                describe_eval_error(&e)
+            ));
+        }
+        if let Err(e) = self.runtime.run_update() {
+            self.report(format!("Server script error: {}", describe_eval_error(&e)));
+        }
+        for e in self.runtime.run_jobs() {
+            self.report(format!("Server job error: {}", describe_eval_error(&e)));
+        }
+        for command in self.runtime.take_due_commands() {
+            if let Err(e) = self.runtime.run_command(&command) {
+                self.report(format!(
+                    "Server error running {}: {}",
+                    command,
+                    describe_eval_error(&e)
+                ));
+            }
+        }
+
+        for error in self.runtime.end_frame(io, query) {
+            self.report(error);
+        }
+
+        let writes = self.runtime.take_shared_writes();
+        self.merge_shared(io, writes);
+    }
+
+    /// Log an error, unless it's the same as the last one
+    fn report(&mut self, error: String) {
+        if self.last_error.as_ref() != Some(&error) {
+            println!("{}", error);
+            self.last_error = Some(error);
+        }
+    }
+}
+
+/// The script the server should run after `uploads`, if they change it. The newest upload wins,
+/// except that an empty one only stops the script if it comes from the client which sent it.
+fn newest_server_script(
+    owner: &mut Option<ClientId>,
+    uploads: impl IntoIterator<Item = (ClientId, ServerScript)>,
+) -> Option<String> {
+    let mut newest = None;
+    for (client, msg) in uploads {
+        if !msg.script.is_empty() {
+            *owner = Some(client);
+        } else if *owner == Some(client) {
+            *owner = None;
+        } else {
+            continue;
+        }
+        newest = Some(msg.script);
+    }
+    newest
+}
+
+/// Store `script` as the one clients start with, or clear it if it's blank, so they fall back to
+/// the default one
+fn set_startup_script(saved_scripts: &mut HashMap<String, String>, script: String) {
+    if script.trim().is_empty() {
+        saved_scripts.remove(STARTUP_SLOT);
+    } else {
+        saved_scripts.insert(STARTUP_SLOT.to_string(), script);
     }
 }
 
 // Defines entry points for the engine to hook into.
 // Calls new() for the appropriate state.
-make_app_state!(ClientState, ServerState);
+#[cfg(feature = "standalone")]
+cimvr_engine_interface::make_app_state!(ClientState, ServerState);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn editor_widgets_match_their_indices() {
+        // Panics if any widget is listed out of order
+        let (schema, state) = editor_widgets();
+        assert_eq!(schema.len(), EDITOR_WIDGET_COUNT);
+        assert_eq!(state.len(), EDITOR_WIDGET_COUNT);
+        assert!(matches!(state[IDX_INPUT], State::TextInput { .. }));
+        assert!(matches!(state[IDX_EXAMPLES], State::Button { .. }));
+    }
+
+    #[test]
+    fn startup_script_overrides_until_cleared() {
+        let mut saved_scripts = HashMap::new();
+        set_startup_script(&mut saved_scripts, "fn update() {}".into());
+        assert_eq!(
+            saved_scripts.get(STARTUP_SLOT).map(String::as_str),
+            Some("fn update() {}")
+        );
+
+        set_startup_script(&mut saved_scripts, " \n".into());
+        assert_eq!(saved_scripts.get(STARTUP_SLOT), None);
+    }
+
+    #[test]
+    fn long_returns_are_truncated() {
+        assert_eq!(describe_return(&Dynamic::UNIT, false), "Returned: ()");
+        assert_eq!(
+            describe_return(&Dynamic::from(42 as rhai::INT), false),
+            "Returned (i64): 42"
+        );
+
+        let long = "é".repeat(MAX_RETURN_CHARS + 1);
+        let text = describe_return(&Dynamic::from(long), false);
+        let value = text
+            .strip_prefix("Returned (string): ")
+            .and_then(|text| text.strip_suffix("…(truncated)"))
+            .unwrap();
+        assert_eq!(value.chars().count(), MAX_RETURN_CHARS);
+    }
+
+    #[test]
+    fn script_errors_echo_their_line() {
+        let mut runtime = ScriptRuntime::new("");
+
+        let script = "fn update() {\n    let x = ;\n}";
+        let Err(CompileError::Parse(e)) = runtime.compile(script) else {
+            panic!("{} should fail to parse", script);
+        };
+        let text = describe_error_in(script, &e);
+        assert!(text.starts_with("line 2, col "), "{}", text);
+        assert!(text.ends_with("\n>> 2: let x = ;"), "{}", text);
+
+        // Errors in functions point at the line which failed, not the call
+        let script = "fn fail() {\n    throw \"oops\";\n}\n\nfail();";
+        let Err(CompileError::Run(e)) = runtime.compile(script) else {
+            panic!("{} should fail to run", script);
+        };
+        let text = describe_error_in(script, &e);
+        assert!(text.ends_with("\n>> 2: throw \"oops\";"), "{}", text);
+
+        // A position outside the script isn't echoed
+        assert_eq!(echo_line("one line", 2), None);
+        assert_eq!(echo_line("one line", 0), None);
+    }
+
+    #[test]
+    fn only_the_owner_stops_the_server_script() {
+        let upload = |client, script: &str| {
+            (
+                ClientId(client),
+                ServerScript {
+                    script: script.into(),
+                },
+            )
+        };
+        let mut owner = None;
+
+        let newest = newest_server_script(&mut owner, [upload(1, "a"), upload(2, "b")]);
+        assert_eq!(newest.as_deref(), Some("b"));
+        assert_eq!(owner, Some(ClientId(2)));
+
+        // Client 1 switching Run on Server off leaves client 2's script running
+        assert_eq!(newest_server_script(&mut owner, [upload(1, "")]), None);
+        assert_eq!(owner, Some(ClientId(2)));
+
+        let newest = newest_server_script(&mut owner, [upload(2, "")]);
+        assert_eq!(newest.as_deref(), Some(""));
+        assert_eq!(owner, None);
+        assert_eq!(newest_server_script(&mut owner, [upload(2, "")]), None);
+    }
+}
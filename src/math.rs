@@ -0,0 +1,437 @@
+//! Vector math exposed to scripts.
+//!
+//! Vectors are plain Rhai arrays of three floats, which is exactly what `Transform::pos`
+//! serializes to. That way the results can be assigned straight into `state.transforms`.
+//! Likewise quaternions are arrays of four floats in `Transform::orient`'s `[x, y, z, w]` order,
+//! and 4x4 matrices are arrays of sixteen floats.
+//!
+//! Scalar math mostly comes from Rhai's standard packages: `sin`, `cos`, `tan`, `atan(y, x)`,
+//! `sqrt`, `floor`, `ceiling` and `round` take floats, while `abs`, `min` and `max` take floats
+//! or integers. This adds the names scripts from other languages tend to reach for instead:
+//! `atan2(y, x)`, `ceil(x)` and `clamp(x, min, max)`.
+use cimvr_common::{
+    glam::{Mat4, Quat, Vec3},
+    Transform,
+};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, FLOAT, INT};
+
+use crate::runtime::script_error;
+
+pub fn register(engine: &mut Engine) {
+    // Named components, so `t.pos.y += 1.0` works on plain arrays
+    for (name, index) in [("x", 0), ("y", 1), ("z", 2), ("w", 3)] {
+        engine.register_get_set(
+            name,
+            move |v: &mut Array| -> Result<FLOAT, Box<EvalAltResult>> {
+                to_float(
+                    v.get(index)
+                        .ok_or_else(|| missing_component(name, v.len()))?,
+                )
+            },
+            move |v: &mut Array, value: FLOAT| -> Result<(), Box<EvalAltResult>> {
+                let len = v.len();
+                *v.get_mut(index)
+                    .ok_or_else(|| missing_component(name, len))? = value.into();
+                Ok(())
+            },
+        );
+    }
+
+    engine.register_fn("vec3", |x: FLOAT, y: FLOAT, z: FLOAT| {
+        from_vec3(Vec3::new(x, y, z))
+    });
+
+    engine.register_fn(
+        "add",
+        |a: Array, b: Array| -> Result<Array, Box<EvalAltResult>> {
+            Ok(from_vec3(to_vec3(&a)? + to_vec3(&b)?))
+        },
+    );
+
+    engine.register_fn(
+        "sub",
+        |a: Array, b: Array| -> Result<Array, Box<EvalAltResult>> {
+            Ok(from_vec3(to_vec3(&a)? - to_vec3(&b)?))
+        },
+    );
+
+    engine.register_fn(
+        "scale",
+        |v: Array, f: FLOAT| -> Result<Array, Box<EvalAltResult>> {
+            Ok(from_vec3(to_vec3(&v)? * f))
+        },
+    );
+
+    engine.register_fn(
+        "dot",
+        |a: Array, b: Array| -> Result<FLOAT, Box<EvalAltResult>> {
+            Ok(to_vec3(&a)?.dot(to_vec3(&b)?))
+        },
+    );
+
+    engine.register_fn(
+        "cross",
+        |a: Array, b: Array| -> Result<Array, Box<EvalAltResult>> {
+            Ok(from_vec3(to_vec3(&a)?.cross(to_vec3(&b)?)))
+        },
+    );
+
+    engine.register_fn("length", |v: Array| -> Result<FLOAT, Box<EvalAltResult>> {
+        Ok(to_vec3(&v)?.length())
+    });
+
+    // Cheaper than length() for comparing distances, since there's no square root
+    engine.register_fn(
+        "length_squared",
+        |v: Array| -> Result<FLOAT, Box<EvalAltResult>> { Ok(to_vec3(&v)?.length_squared()) },
+    );
+
+    // Unit vector pointing the same way as `v`. A zero (or non-finite) vector has no direction,
+    // so it throws an ArgumentError rather than returning NaNs, which a script can catch.
+    engine.register_fn(
+        "normalize",
+        |v: Array| -> Result<Array, Box<EvalAltResult>> {
+            let v = to_vec3(&v)?.try_normalize().ok_or_else(|| {
+                script_error("ArgumentError", "normalize() needs a non-zero vector")
+            })?;
+            Ok(from_vec3(v))
+        },
+    );
+
+    // Distance between two points. distance() does the same for entities, given their ids.
+    engine.register_fn(
+        "distance_to",
+        |a: Array, b: Array| -> Result<FLOAT, Box<EvalAltResult>> {
+            Ok(to_vec3(&a)?.distance(to_vec3(&b)?))
+        },
+    );
+
+    // Easing, e.g. `t.pos = lerp(t.pos, target, 0.1)` to move part of the way each frame
+    engine.register_fn("lerp", |a: FLOAT, b: FLOAT, t: FLOAT| a + (b - a) * t);
+    engine.register_fn(
+        "lerp",
+        |a: Array, b: Array, t: FLOAT| -> Result<Array, Box<EvalAltResult>> {
+            Ok(from_vec3(to_vec3(&a)?.lerp(to_vec3(&b)?, t)))
+        },
+    );
+
+    engine.register_fn("atan2", |y: FLOAT, x: FLOAT| y.atan2(x));
+    engine.register_fn("ceil", |x: FLOAT| x.ceil());
+
+    // Both bounds are inclusive. Unlike f32::clamp() this doesn't panic when min > max, which
+    // a script shouldn't be able to do; the result is just min.
+    engine.register_fn("clamp", |x: FLOAT, min: FLOAT, max: FLOAT| {
+        x.min(max).max(min)
+    });
+    engine.register_fn("clamp", |x: INT, min: INT, max: INT| x.min(max).max(min));
+
+    engine.register_fn("smoothstep", |edge0: FLOAT, edge1: FLOAT, x: FLOAT| {
+        // Equal edges would divide by zero; treat them as a hard step
+        if edge0 == edge1 {
+            return if x < edge0 { 0.0 } else { 1.0 };
+        }
+        let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    });
+
+    engine.register_fn(
+        "clamp_position",
+        |pos: Array, min: Array, max: Array| -> Result<Array, Box<EvalAltResult>> {
+            let (min, max) = to_bounds(&min, &max)?;
+            Ok(from_vec3(to_vec3(&pos)?.clamp(min, max)))
+        },
+    );
+
+    engine.register_fn(
+        "quat_from_axis_angle",
+        |axis: Array, radians: FLOAT| -> Result<Array, Box<EvalAltResult>> {
+            let axis = to_vec3(&axis)?.try_normalize().ok_or_else(|| {
+                script_error(
+                    "ArgumentError",
+                    "quat_from_axis_angle() needs a non-zero axis",
+                )
+            })?;
+            Ok(from_quat(Quat::from_axis_angle(axis, radians)))
+        },
+    );
+
+    engine.register_fn(
+        "quat_mul",
+        |a: Array, b: Array| -> Result<Array, Box<EvalAltResult>> {
+            Ok(from_quat(to_quat(&a)? * to_quat(&b)?))
+        },
+    );
+
+    engine.register_fn(
+        "rotate_vec",
+        |q: Array, v: Array| -> Result<Array, Box<EvalAltResult>> {
+            Ok(from_vec3(to_quat(&q)? * to_vec3(&v)?))
+        },
+    );
+
+    // Matrices, for composing transforms, e.g. a child's transform relative to its parent:
+    // `mat4_to_transform(mat4_mul(mat4(parent), mat4(child)))`
+    engine.register_fn("mat4", || from_mat4(Mat4::IDENTITY));
+    engine.register_fn("mat4", |t: Transform| {
+        from_mat4(Mat4::from_rotation_translation(t.orient, t.pos))
+    });
+
+    engine.register_fn(
+        "mat4_mul",
+        |a: Array, b: Array| -> Result<Array, Box<EvalAltResult>> {
+            Ok(from_mat4(to_mat4(&a)? * to_mat4(&b)?))
+        },
+    );
+
+    engine.register_fn(
+        "mat4_transform_point",
+        |m: Array, v: Array| -> Result<Array, Box<EvalAltResult>> {
+            Ok(from_vec3(to_mat4(&m)?.transform_point3(to_vec3(&v)?)))
+        },
+    );
+
+    // Any scale in the matrix is dropped, since Transform can't hold it
+    engine.register_fn(
+        "mat4_to_transform",
+        |m: Array| -> Result<Transform, Box<EvalAltResult>> {
+            let (_scale, orient, pos) = to_mat4(&m)?.to_scale_rotation_translation();
+            Ok(Transform {
+                pos,
+                orient: orient.normalize(),
+            })
+        },
+    );
+}
+
+fn missing_component(name: &str, len: usize) -> Box<EvalAltResult> {
+    script_error(
+        "TypeError",
+        format!("No .{} component in an array of {} elements", name, len),
+    )
+}
+
+/// Read a number, accepting integers too since literals like `[0, 1, 0]` are easy to write
+pub fn to_float(d: &Dynamic) -> Result<FLOAT, Box<EvalAltResult>> {
+    d.as_float()
+        .or_else(|_| d.as_int().map(|i| i as FLOAT))
+        .map_err(|ty| script_error("TypeError", format!("Expected a number, found {}", ty)))
+}
+
+pub fn to_vec3(v: &Array) -> Result<Vec3, Box<EvalAltResult>> {
+    match v.as_slice() {
+        [x, y, z] => Ok(Vec3::new(to_float(x)?, to_float(y)?, to_float(z)?)),
+        _ => Err(script_error(
+            "TypeError",
+            format!("Expected a vec3 of 3 numbers, found {} elements", v.len()),
+        )),
+    }
+}
+
+/// Corners of a box, checking `min` isn't above `max` on any axis
+pub fn to_bounds(min: &Array, max: &Array) -> Result<(Vec3, Vec3), Box<EvalAltResult>> {
+    let (min, max) = (to_vec3(min)?, to_vec3(max)?);
+    if min.cmpgt(max).any() {
+        return Err(script_error(
+            "ArgumentError",
+            format!("Bounds minimum {} is above maximum {}", min, max),
+        ));
+    }
+    Ok((min, max))
+}
+
+pub fn from_vec3(v: Vec3) -> Array {
+    vec![v.x.into(), v.y.into(), v.z.into()]
+}
+
+pub fn to_quat(q: &Array) -> Result<Quat, Box<EvalAltResult>> {
+    match q.as_slice() {
+        [x, y, z, w] => Ok(Quat::from_xyzw(
+            to_float(x)?,
+            to_float(y)?,
+            to_float(z)?,
+            to_float(w)?,
+        )),
+        _ => Err(script_error(
+            "TypeError",
+            format!(
+                "Expected a quaternion of 4 numbers, found {} elements",
+                q.len()
+            ),
+        )),
+    }
+}
+
+pub fn from_quat(q: Quat) -> Array {
+    vec![q.x.into(), q.y.into(), q.z.into(), q.w.into()]
+}
+
+/// Matrices are arrays of 16 numbers in column-major order, as glam stores them
+pub fn to_mat4(m: &Array) -> Result<Mat4, Box<EvalAltResult>> {
+    if m.len() != 16 {
+        return Err(script_error(
+            "TypeError",
+            format!("Expected a mat4 of 16 numbers, found {} elements", m.len()),
+        ));
+    }
+
+    let mut cols = [0.0; 16];
+    for (col, value) in cols.iter_mut().zip(m) {
+        *col = to_float(value)?;
+    }
+    Ok(Mat4::from_cols_array(&cols))
+}
+
+pub fn from_mat4(m: Mat4) -> Array {
+    m.to_cols_array().iter().map(|&x| x.into()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::{FRAC_PI_2, PI};
+
+    use super::*;
+
+    fn engine() -> Engine {
+        let mut engine = Engine::new();
+        register(&mut engine);
+        engine
+    }
+
+    fn eval_vec3(script: &str) -> Vec3 {
+        to_vec3(&engine().eval::<Array>(script).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn vector_helpers() {
+        assert_eq!(eval_vec3("vec3(1., 2., 3.)"), Vec3::new(1.0, 2.0, 3.0));
+        assert_eq!(
+            eval_vec3("add(vec3(1., 2., 3.), [1, 1, 1])"),
+            Vec3::new(2.0, 3.0, 4.0)
+        );
+        assert_eq!(
+            eval_vec3("sub(vec3(1., 2., 3.), [1, 1, 1])"),
+            Vec3::new(0.0, 1.0, 2.0)
+        );
+        assert_eq!(
+            eval_vec3("scale(vec3(1., 2., 3.), 2.)"),
+            Vec3::new(2.0, 4.0, 6.0)
+        );
+        assert_eq!(eval_vec3("cross([1, 0, 0], [0, 1, 0])"), Vec3::Z);
+
+        let engine = engine();
+        assert_eq!(
+            engine.eval::<FLOAT>("dot([1, 2, 3], [4, 5, 6])").unwrap(),
+            32.0
+        );
+        assert_eq!(engine.eval::<FLOAT>("length([3, 4, 0])").unwrap(), 5.0);
+
+        let v = engine
+            .eval::<Array>("let v = vec3(1., 2., 3.); v.y += 1.; v")
+            .unwrap();
+        assert_eq!(to_vec3(&v).unwrap(), Vec3::new(1.0, 3.0, 3.0));
+        assert!(engine.eval::<Array>("add([1, 2], [1, 2, 3])").is_err());
+    }
+
+    #[test]
+    fn quaternion_helpers() {
+        let engine = engine();
+        let quat = |script: &str| to_quat(&engine.eval::<Array>(script).unwrap()).unwrap();
+
+        let quarter = quat("quat_from_axis_angle([0, 2, 0], PI() / 2.)");
+        assert!(quarter.abs_diff_eq(Quat::from_rotation_y(FRAC_PI_2), 1e-6));
+        assert!(engine
+            .eval::<Array>("quat_from_axis_angle([0, 0, 0], 1.)")
+            .is_err());
+
+        let half = quat("let q = quat_from_axis_angle([0, 1, 0], PI() / 2.); quat_mul(q, q)");
+        assert!(half.abs_diff_eq(Quat::from_rotation_y(PI), 1e-6));
+
+        let rotated =
+            eval_vec3("rotate_vec(quat_from_axis_angle([0, 1, 0], PI() / 2.), [1, 0, 0])");
+        assert!(rotated.abs_diff_eq(Vec3::NEG_Z, 1e-6));
+    }
+
+    #[test]
+    fn easing_endpoints() {
+        let engine = engine();
+        let float = |script: &str| engine.eval::<FLOAT>(script).unwrap();
+
+        assert_eq!(float("lerp(2., 4., 0.)"), 2.0);
+        assert_eq!(float("lerp(2., 4., 1.)"), 4.0);
+        assert_eq!(float("lerp(2., 4., 0.5)"), 3.0);
+        assert_eq!(eval_vec3("lerp([0, 0, 0], [2, 4, 6], 0.)"), Vec3::ZERO);
+        assert_eq!(
+            eval_vec3("lerp([0, 0, 0], [2, 4, 6], 1.)"),
+            Vec3::new(2.0, 4.0, 6.0)
+        );
+
+        assert_eq!(float("smoothstep(1., 3., 1.)"), 0.0);
+        assert_eq!(float("smoothstep(1., 3., 3.)"), 1.0);
+        assert_eq!(float("smoothstep(1., 3., 2.)"), 0.5);
+        // Outside the edges it clamps rather than overshooting
+        assert_eq!(float("smoothstep(1., 3., -5.)"), 0.0);
+        assert_eq!(float("smoothstep(1., 3., 10.)"), 1.0);
+        // Equal edges are a hard step
+        assert_eq!(float("smoothstep(1., 1., 0.)"), 0.0);
+        assert_eq!(float("smoothstep(1., 1., 1.)"), 1.0);
+    }
+
+    #[test]
+    fn scalar_helpers() {
+        let engine = engine();
+        let float = |script: &str| engine.eval::<FLOAT>(script).unwrap();
+
+        assert_eq!(float("atan2(1., 0.)"), FRAC_PI_2);
+        assert_eq!(float("atan2(0., -1.)"), PI);
+        assert_eq!(float("ceil(1.2)"), 2.0);
+        assert_eq!(float("ceil(-1.2)"), -1.0);
+
+        assert_eq!(float("clamp(5., 0., 1.)"), 1.0);
+        assert_eq!(float("clamp(-5., 0., 1.)"), 0.0);
+        assert_eq!(float("clamp(0.5, 0., 1.)"), 0.5);
+        assert_eq!(engine.eval::<INT>("clamp(7, 0, 3)").unwrap(), 3);
+        // Inverted bounds give min instead of panicking like f32::clamp()
+        assert_eq!(float("clamp(0.5, 1., 0.)"), 1.0);
+        assert_eq!(engine.eval::<INT>("clamp(2, 5, 1)").unwrap(), 5);
+    }
+
+    #[test]
+    fn matrix_helpers() {
+        let mut engine = engine();
+        crate::transform::register(&mut engine);
+
+        let t = engine
+            .eval::<Transform>(
+                "let parent = transform([1, 0, 0], quat_from_axis_angle([0, 1, 0], PI() / 2.));
+                let child = transform([0, 0, -1], [0, 0, 0, 1]);
+                mat4_to_transform(mat4_mul(mat4(parent), mat4(child)))",
+            )
+            .unwrap();
+        // The child is one unit in front of the parent, which is turned to face -X
+        assert!(t.pos.abs_diff_eq(Vec3::new(0.0, 0.0, 0.0), 1e-6));
+        assert!(t.orient.abs_diff_eq(Quat::from_rotation_y(FRAC_PI_2), 1e-6));
+
+        let point = eval_vec3("mat4_transform_point(mat4(), [1, 2, 3])");
+        assert_eq!(point, Vec3::new(1.0, 2.0, 3.0));
+        assert!(engine.eval::<Array>("mat4_mul(mat4(), [1, 2, 3])").is_err());
+    }
+
+    #[test]
+    fn length_helpers() {
+        let engine = engine();
+        let float = |script: &str| engine.eval::<FLOAT>(script).unwrap();
+
+        assert_eq!(float("length_squared([1, 2, 2])"), 9.0);
+        assert_eq!(float("distance_to([1, 0, 0], [1, 3, 4])"), 5.0);
+        assert_eq!(float("distance_to([1, 2, 3], [1, 2, 3])"), 0.0);
+
+        assert_eq!(eval_vec3("normalize([0, 0, 5])"), Vec3::Z);
+        assert!((float("length(normalize([1, 2, 3]))") - 1.0).abs() < 1e-6);
+        // A zero vector has no direction, and the error can be caught
+        assert!(engine.eval::<Array>("normalize([0, 0, 0])").is_err());
+        let caught = engine
+            .eval::<String>("let caught = \"\"; try { normalize([0, 0, 0]); } catch (e) { caught = e.error; } caught")
+            .unwrap();
+        assert_eq!(caught, "ArgumentError");
+    }
+}
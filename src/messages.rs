@@ -0,0 +1,105 @@
+//! Messages exchanged between the client and server halves of the plugin
+use cimvr_engine_interface::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Slot used by the editor's Save/Load buttons
+pub const DEFAULT_SLOT: &str = "default";
+
+/// Slot the server fills with the script from StartupScript, if one was sent. Clients load it in
+/// place of the default script when they start, which also tells the server to send them the
+/// shared state so far.
+pub const STARTUP_SLOT: &str = "startup";
+
+/// Client -> server: store a script under a named slot, replacing what was there
+#[derive(Message, Serialize, Deserialize, Debug, Clone)]
+#[locality("Remote")]
+pub struct ScriptSaved {
+    pub slot: String,
+    pub script: String,
+}
+
+/// Client -> server: ask for the script stored in a slot
+#[derive(Message, Serialize, Deserialize, Debug, Clone)]
+#[locality("Remote")]
+pub struct ScriptLoad {
+    pub slot: String,
+}
+
+/// Server -> client: reply to ScriptLoad. `script` is None if nothing was saved in the slot.
+#[derive(Message, Serialize, Deserialize, Debug, Clone)]
+#[locality("Remote")]
+pub struct ScriptLoaded {
+    pub slot: String,
+    pub script: Option<String>,
+}
+
+/// Client -> server: replace the script the server runs each frame. An empty script stops it,
+/// but only from the client which sent the running one, so a client switching Run on Server off
+/// doesn't stop a script another client uploaded since.
+#[derive(Message, Serialize, Deserialize, Debug, Clone)]
+#[locality("Remote")]
+pub struct ServerScript {
+    pub script: String,
+}
+
+/// Client -> server: the running script's own entries in `state` as a JSON map, sent every so
+/// often. The server keeps the latest one for each client and script name in memory, so the
+/// state survives the client's plugin reloading. It's lost if the client reconnects, since it
+/// comes back as a different client, or if the server restarts.
+#[derive(Message, Serialize, Deserialize, Debug, Clone)]
+#[locality("Remote")]
+pub struct StateSnapshot {
+    /// Name of the script the state belongs to
+    pub slot: String,
+    pub json: String,
+}
+
+/// Client -> server: ask for the last StateSnapshot this client sent for a script, once it has
+/// started up
+#[derive(Message, Serialize, Deserialize, Debug, Clone)]
+#[locality("Remote")]
+pub struct StateSnapshotLoad {
+    pub slot: String,
+}
+
+/// Server -> client: reply to StateSnapshotLoad. `json` is None if the client hasn't sent a
+/// snapshot for the script.
+#[derive(Message, Serialize, Deserialize, Debug, Clone)]
+#[locality("Remote")]
+pub struct StateSnapshotLoaded {
+    pub slot: String,
+    pub json: Option<String>,
+}
+
+/// Client -> server: set keys of the shared state, replacing their values. `json` is a map.
+#[derive(Message, Serialize, Deserialize, Debug, Clone)]
+#[locality("Remote")]
+pub struct SharedStateWrite {
+    pub json: String,
+}
+
+/// Server -> client: the whole shared state, as a JSON map, sent whenever it changes and to each
+/// client as it starts
+#[derive(Message, Serialize, Deserialize, Debug, Clone)]
+#[locality("Remote")]
+pub struct SharedStateSync {
+    pub json: String,
+}
+
+/// Other plugins -> server: set the script clients start with, so a session can be set up from
+/// outside without going through the editor. Send it when the server starts; clients which
+/// started earlier keep their script. An empty script clears it.
+#[derive(Message, Serialize, Deserialize, Debug, Clone)]
+#[locality("Local")]
+pub struct StartupScript {
+    pub script: String,
+}
+
+/// Sent by scripts with message(tag, payload), for other plugins on the same side to pick up.
+/// `json` is the payload, always a JSON map. Scripts see these in state.messages.
+#[derive(Message, Serialize, Deserialize, Debug, Clone)]
+#[locality("Local")]
+pub struct ScriptMessage {
+    pub tag: String,
+    pub json: String,
+}
@@ -0,0 +1,125 @@
+//! Per-function profiling of scripts, shown in the editor while "Profile" is checked.
+//!
+//! Rhai's debugger interface reports every expression evaluated along with the call stack, so
+//! each one is charged to the innermost function running. There's no wall-clock timer in the
+//! plugin, so the number of expressions (steps) stands in for time, like operations do for
+//! update() as a whole.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use rhai::debugger::{DebuggerCommand, DebuggerEvent};
+use rhai::Engine;
+
+/// Name steps outside any function are charged to, such as a command's own statements
+const TOP_LEVEL: &str = "<top level>";
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct FunctionProfile {
+    pub calls: u64,
+    pub steps: u64,
+}
+
+#[derive(Default)]
+struct Profile {
+    functions: HashMap<String, FunctionProfile>,
+    /// Call stack depth at the last step, to tell when a function is entered
+    depth: usize,
+}
+
+/// Counts shared with the engine's debugger callback. Profiling is off while this holds None.
+#[derive(Default, Clone)]
+pub struct Profiler(Arc<Mutex<Option<Profile>>>);
+
+impl Profiler {
+    pub fn register(&self, engine: &mut Engine) {
+        let profile = self.0.clone();
+        // register_debugger() is marked deprecated only to flag that its signature may change
+        #[allow(deprecated)]
+        engine.register_debugger(
+            |_, debugger| debugger,
+            move |context, event, _node, _source, _pos| {
+                let mut profile = profile.lock().unwrap();
+                let Some(profile) = profile.as_mut() else {
+                    // Stops the debugger being called again for the rest of the run
+                    return Ok(DebuggerCommand::Continue);
+                };
+
+                // Functions called directly, like update(), are already on the stack at the start
+                if let DebuggerEvent::Start = event {
+                    profile.depth = 0;
+                    return Ok(DebuggerCommand::StepInto);
+                }
+
+                let stack = context.global_runtime_state().debugger().call_stack();
+                let name = stack
+                    .last()
+                    .map_or(TOP_LEVEL.to_string(), |frame| frame.fn_name.to_string());
+                let entered = stack.len() > profile.depth;
+                profile.depth = stack.len();
+
+                let function = profile.functions.entry(name).or_default();
+                if entered {
+                    function.calls += 1;
+                }
+                function.steps += 1;
+                Ok(DebuggerCommand::StepInto)
+            },
+        );
+    }
+
+    /// Start counting from zero, or stop and drop the counts
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut profile = self.0.lock().unwrap();
+        if enabled != profile.is_some() {
+            *profile = enabled.then(Profile::default);
+        }
+    }
+
+    /// Counts for each function called since profiling was enabled, with the most steps first
+    pub fn report(&self) -> Vec<(String, FunctionProfile)> {
+        let profile = self.0.lock().unwrap();
+        let mut functions: Vec<_> = profile
+            .iter()
+            .flat_map(|profile| &profile.functions)
+            .map(|(name, function)| (name.clone(), *function))
+            .collect();
+        functions.sort_by(|a, b| b.1.steps.cmp(&a.1.steps).then_with(|| a.0.cmp(&b.0)));
+        functions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_calls_and_steps_per_function() {
+        let mut engine = Engine::new();
+        let profiler = Profiler::default();
+        profiler.register(&mut engine);
+        let script = "
+            fn countdown(n) { if n > 0 { countdown(n - 1) } }
+            fn twice() { countdown(1); countdown(1) }
+            twice();
+            countdown(2);
+        ";
+
+        // Nothing is counted until it's enabled
+        engine.run(script).unwrap();
+        assert!(profiler.report().is_empty());
+
+        profiler.set_enabled(true);
+        engine.run(script).unwrap();
+        let report: HashMap<_, _> = profiler.report().into_iter().collect();
+        assert_eq!(report["twice"].calls, 1);
+        assert_eq!(report["countdown"].calls, 7);
+        assert!(report["countdown"].steps > report["twice"].steps);
+        assert!(report[TOP_LEVEL].steps > 0);
+
+        // The heaviest function comes first
+        assert_eq!(profiler.report()[0].0, "countdown");
+
+        profiler.set_enabled(false);
+        assert!(profiler.report().is_empty());
+    }
+}
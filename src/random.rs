@@ -0,0 +1,96 @@
+//! Seeded random numbers for procedural scripts.
+//!
+//! Uses SplitMix64, which is plenty for placement and jitter. Every run starts from the same
+//! seed, so a script behaves the same way each time until it calls `seed_random()`.
+use std::sync::{Arc, Mutex};
+
+use rhai::{Engine, EvalAltResult, FLOAT, INT};
+
+use crate::runtime::script_error;
+
+const DEFAULT_SEED: u64 = 0x5EED;
+
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform float in [0, 1), from the top bits which fit in the mantissa
+    fn next_float(&mut self) -> FLOAT {
+        (self.next() >> (64 - FLOAT::MANTISSA_DIGITS)) as FLOAT
+            / (1u64 << FLOAT::MANTISSA_DIGITS) as FLOAT
+    }
+}
+
+pub fn register(engine: &mut Engine) {
+    let rng = Arc::new(Mutex::new(SplitMix64(DEFAULT_SEED)));
+
+    let seed_rng = rng.clone();
+    engine.register_fn("seed_random", move |seed: INT| {
+        *seed_rng.lock().unwrap() = SplitMix64(seed as u64);
+    });
+
+    let random_rng = rng.clone();
+    engine.register_fn("random", move || random_rng.lock().unwrap().next_float());
+
+    let float_rng = rng.clone();
+    engine.register_fn("random_range", move |min: FLOAT, max: FLOAT| {
+        min + (max - min) * float_rng.lock().unwrap().next_float()
+    });
+
+    // Like `min..max`, the maximum is excluded
+    engine.register_fn(
+        "random_range",
+        move |min: INT, max: INT| -> Result<INT, Box<EvalAltResult>> {
+            if max <= min {
+                return Err(script_error(
+                    "ArgumentError",
+                    format!("random_range({}, {}) is an empty range", min, max),
+                ));
+            }
+            let span = max.abs_diff(min);
+            Ok(min.wrapping_add((rng.lock().unwrap().next() % span) as INT))
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(engine: &Engine) -> Vec<FLOAT> {
+        (0..5).map(|_| engine.eval("random()").unwrap()).collect()
+    }
+
+    #[test]
+    fn reseeding_repeats_the_sequence() {
+        let mut engine = Engine::new();
+        register(&mut engine);
+
+        engine.run("seed_random(42)").unwrap();
+        let first = draw(&engine);
+        engine.run("seed_random(42)").unwrap();
+        assert_eq!(draw(&engine), first);
+
+        engine.run("seed_random(43)").unwrap();
+        assert_ne!(draw(&engine), first);
+        assert!(first.iter().all(|x| (0.0..1.0).contains(x)));
+    }
+
+    #[test]
+    fn int_ranges_exclude_the_maximum() {
+        let mut engine = Engine::new();
+        register(&mut engine);
+        for _ in 0..100 {
+            let x: INT = engine.eval("random_range(-2, 3)").unwrap();
+            assert!((-2..3).contains(&x));
+        }
+        assert!(engine.eval::<INT>("random_range(3, 3)").is_err());
+    }
+}
@@ -0,0 +1,2585 @@
+//! Script execution shared by the client's editor and the server.
+//!
+//! Each frame, the components a script can see are copied into the `state` map in its scope.
+//! Then `update()` and any commands run, and the (possibly edited) components are copied back.
+use std::any::TypeId;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use cimvr_common::{
+    glam::Vec3,
+    render::{MeshHandle, Primitive, Render},
+    FrameTime, Transform,
+};
+use cimvr_engine_interface::{prelude::*, println};
+use rhai::{
+    format_map_as_json, Array, CallFnOptions, Dynamic, Engine, EvalAltResult, FnPtr,
+    ImmutableString, Module, NativeCallContext, ParseErrorType, Position, Scope, AST, FLOAT, INT,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::color::{self, Color, TintedCubes};
+use crate::messages::ScriptMessage;
+use crate::profile::Profiler;
+use crate::{math, random, transform};
+
+const BUILTIN_SCRIPT: &str = include_str!("builtins.rhai");
+
+/// Upper bound on spawn_entity() calls per frame, which is also how many ids we reserve
+const MAX_SPAWNS_PER_FRAME: usize = 64;
+
+/// Number of log_warn()/log_error() lines kept for display
+const LOG_CAPACITY: usize = 20;
+
+/// Bytes of print() output kept for display; the oldest lines are dropped past this
+const PRINT_CAPACITY: usize = 4096;
+
+/// Operations a single run (update() or a command) may take before it's aborted, so an
+/// accidental infinite loop can't hang the plugin. Scripts can change it with
+/// set_operation_limit().
+const DEFAULT_OPERATION_LIMIT: u64 = 500_000;
+
+/// Deepest chain of nested function calls, which mostly limits recursion
+const MAX_CALL_LEVELS: usize = 48;
+
+/// Deepest nesting of expressions at the top level, and within functions
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_FUNCTION_EXPR_DEPTH: usize = 32;
+
+/// Variables resolved from frame_vars when the script hasn't declared its own
+const FRAME_VARS: [&str; 3] = ["dt", "time", "transforms"];
+
+/// Entries of `state` which begin_frame() fills in besides the components. They're replaced
+/// every frame, so snapshot_state() leaves them out.
+const FRAME_STATE: [&str; 11] = [
+    "dt",
+    "entity_count",
+    "selected",
+    "shared",
+    "ui",
+    "clamped",
+    "messages",
+    "perf",
+    "colors",
+    "visible",
+    "prev_transforms",
+];
+
+/// Largest state.dt handed to scripts, so a hitch doesn't make animations jump
+const MAX_DT: f32 = 0.1;
+
+/// Mesh given to entities spawned from scripts. Uploaded by the client.
+pub const CUBE_HANDLE: MeshHandle = MeshHandle::new(pkg_namespace!("RhaiCube"));
+
+/// Components copied into `state` each frame and written back at the end of it. Add an entry
+/// here to expose another component type to scripts. Transform must come first, since native
+/// functions like find_near() look entities up by their transforms.
+pub fn script_components() -> Vec<ComponentField> {
+    vec![
+        ComponentField::native::<Transform>("transforms", Access::Write),
+        // Scripts may drop entries from state.renders; those entities are left untouched
+        ComponentField::serde::<Render>("renders", Access::Write),
+    ]
+}
+
+/// ECS changes requested by native functions while a script runs. These closures can't reach
+/// EngineIo, so requests are queued here and applied in end_frame() once the script
+/// (update() and any command) has returned, in the order they were made.
+#[derive(Default)]
+struct ScriptRequests {
+    /// Entities created ahead of time, so spawn_entity() can hand out an id immediately
+    reserved: Vec<EntityId>,
+    /// Reserved entities which should receive a Transform and Render. The Render (and color) is
+    /// copied from the second entity if there is one, otherwise it's a plain cube.
+    spawns: Vec<(EntityId, Transform, Option<EntityId>)>,
+    /// Entities whose Render should be restored (true) or removed (false), applied after spawns
+    render_changes: Vec<(EntityId, bool)>,
+    /// Entities to remove, applied after everything else
+    deletes: Vec<EntityId>,
+}
+
+/// A script which isn't running, along with its scope and whatever it left scheduled, so it can
+/// be switched back to later
+#[derive(Default)]
+pub struct ParkedScript {
+    script: String,
+    ast: AST,
+    scope: Scope<'static>,
+    jobs: Vec<Job>,
+    key_bindings: HashMap<String, String>,
+    deferred: Vec<(Due, String)>,
+}
+
+/// When a command scheduled with after() or after_frames() should run
+#[derive(Clone, Copy)]
+enum Due {
+    /// Seconds since the first frame, as returned by now()
+    Time(FLOAT),
+    /// Frames since the runtime was created
+    Frame(u64),
+}
+
+/// Function passed to start_job(), called once a frame until it returns true
+struct Job {
+    function: FnPtr,
+    /// Functions of the script or command which started the job. Closures are compiled into
+    /// whatever defined them, so they can only be called alongside it.
+    functions: AST,
+}
+
+pub struct ScriptRuntime {
+    pub(crate) engine: Engine,
+    pub(crate) scope: Scope<'static>,
+    /// Source of the current script, even if it failed to compile
+    pub(crate) script: String,
+    /// The last script which compiled successfully
+    ast: AST,
+    requests: Arc<Mutex<ScriptRequests>>,
+    /// Recent log_warn()/log_error() lines
+    pub(crate) log: Arc<Mutex<VecDeque<String>>>,
+    /// print() and debug() output since the last take_prints()
+    prints: Arc<Mutex<String>>,
+    /// Latest value passed to watch() under each name since the last take_watches()
+    watches: Arc<Mutex<BTreeMap<String, String>>>,
+    /// Commands registered with bind_key(), by key name
+    key_bindings: Arc<Mutex<HashMap<String, String>>>,
+    /// Functions passed to start_job() during the current run, not yet picked up by adopt_jobs()
+    started_jobs: Arc<Mutex<Vec<FnPtr>>>,
+    /// Jobs still running, in the order they were started
+    jobs: Vec<Job>,
+    /// Commands from after() and after_frames() which haven't run yet, in the order they were
+    /// scheduled
+    deferred: Arc<Mutex<Vec<(Due, String)>>>,
+    /// Frames begun so far, for after_frames()
+    frame_count: Arc<AtomicU64>,
+    /// Whether init() has yet to run for the last script compiled
+    init_pending: bool,
+    /// Transforms as copied into state.transforms at the start of this frame, for native
+    /// functions which look entities up. Edits made by the script aren't reflected here.
+    transforms: Arc<Mutex<BTreeMap<String, Transform>>>,
+    /// Component types copied in and out of `state`, from script_components()
+    components: Vec<ComponentField>,
+    /// Each component type as copied into `state` this frame, by field, so the write-back can
+    /// tell which entries the script changed
+    copies: HashMap<&'static str, Copies>,
+    /// This frame's `dt`, `time` and `transforms`, which scripts can read without `state.`
+    frame_vars: Arc<Mutex<rhai::Map>>,
+    /// Operations performed so far by the current run, or by the last one
+    operations: Arc<AtomicU64>,
+    /// Operations the last update() took
+    update_operations: u64,
+    /// Per-function counts, while the editor's "Profile" is checked
+    pub(crate) profiler: Profiler,
+    /// Shared state as last sent by the server, shown to scripts as state.shared
+    shared: rhai::Map,
+    /// Changes to the shared state made with share() since the last take_shared_writes()
+    shared_writes: Arc<Mutex<rhai::Map>>,
+    /// Box set by set_world_bounds(), if any
+    bounds: Arc<Mutex<Option<(Vec3, Vec3)>>>,
+    /// Entities whose positions were clamped to the bounds last frame
+    clamped: Array,
+    /// Editor widgets shown to scripts as state.ui
+    ui: rhai::Map,
+    /// Messages from message(), sent at the end of the frame
+    outbox: Arc<Mutex<Vec<ScriptMessage>>>,
+    /// Renders taken off entities by remove_render(), for add_render() to put back
+    hidden: HashMap<EntityId, Render>,
+    /// Colors given to entities through state.colors, by id
+    colors: HashMap<String, Color>,
+    tinted_cubes: TintedCubes,
+    /// Ids of selected entities, in the order they were selected. Kept across frames, minus any
+    /// entities which no longer exist.
+    selection: Arc<Mutex<Vec<String>>>,
+    /// Ids of the entities in each group made with tag(), minus any which no longer exist
+    groups: Arc<Mutex<HashMap<String, BTreeSet<String>>>>,
+    /// Seconds since the previous frame, as given to scripts in state.dt
+    dt: FLOAT,
+    /// Engine time at the previous begin_frame() call
+    last_frame_time: Option<f32>,
+    /// Engine time at the first frame, which now() counts from
+    start_time: Option<f32>,
+    /// Seconds from start_time to the current frame, returned by now()
+    elapsed: Arc<Mutex<FLOAT>>,
+}
+
+/// The query a system running scripts must register. It should also subscribe to FrameTime and
+/// ScriptMessage.
+/// Only entities with every component in script_components() are visible to scripts.
+pub fn transforms_query() -> Query {
+    components_query(&script_components())
+}
+
+/// Like transforms_query(), for a runtime made with ScriptRuntime::with_components()
+pub fn components_query(components: &[ComponentField]) -> Query {
+    components.iter().fold(Query::new(), |query, component| {
+        (component.intersect)(query, component.access)
+    })
+}
+
+impl ScriptRuntime {
+    pub fn new(script: &str) -> Self {
+        Self::with_components(script, script_components())
+    }
+
+    /// A runtime exposing `components` to scripts instead of script_components(). Like there,
+    /// Transform must come first; this panics if it doesn't.
+    pub fn with_components(script: &str, components: Vec<ComponentField>) -> Self {
+        assert!(
+            components
+                .first()
+                .is_some_and(|c| c.component == TypeId::of::<Transform>()),
+            "Transform must be the first component exposed to scripts"
+        );
+
+        let mut engine = Engine::new();
+        // Printed text goes to the host log, and is kept for the UI to pick up
+        let prints = Arc::new(Mutex::new(String::new()));
+        let print_buffer = prints.clone();
+        engine.on_print(move |s: &str| {
+            println!("{}", s);
+            push_line(&mut print_buffer.lock().unwrap(), s, PRINT_CAPACITY);
+        });
+        // debug() output goes the same way, tagged with where it came from, like
+        // `debug@line 3: [1.0, 2.0, 3.0]`. The value is already formatted with to_debug().
+        let debug_buffer = prints.clone();
+        engine.on_debug(move |s, _source, pos| {
+            let line = match pos.line() {
+                Some(line) => format!("debug@line {}: {}", line, s),
+                None => format!("debug: {}", s),
+            };
+            println!("{}", line);
+            push_line(&mut debug_buffer.lock().unwrap(), &line, PRINT_CAPACITY);
+        });
+        // Show a value in the UI without printing it every frame. Later calls with the same name
+        // replace the value.
+        let watches: Arc<Mutex<BTreeMap<String, String>>> = Default::default();
+        let watch_values = watches.clone();
+        engine.register_fn("watch", move |name: &str, value: Dynamic| {
+            watch_values
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), value.to_string());
+        });
+
+        // Run `command` whenever `key` is pressed, such as
+        // `bind_key("Space", "spawn_entity(Transform())")`. Keys are named as in KeyCode, and
+        // binding a key again replaces its command. Only the client sees key presses, only while
+        // Key Bindings is checked, and recompiling the script drops every binding.
+        let key_bindings: Arc<Mutex<HashMap<String, String>>> = Default::default();
+        let bind_keys = key_bindings.clone();
+        engine.register_fn("bind_key", move |key: &str, command: &str| {
+            bind_keys
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), command.to_string());
+        });
+
+        math::register(&mut engine);
+        color::register(&mut engine);
+        random::register(&mut engine);
+        transform::register(&mut engine);
+
+        // Keep buggy or malicious scripts from overflowing the stack
+        engine.set_max_call_levels(MAX_CALL_LEVELS);
+        engine.set_max_expr_depths(MAX_EXPR_DEPTH, MAX_FUNCTION_EXPR_DEPTH);
+
+        // Abort runaway scripts. The operation count starts over for each run.
+        let operation_limit = Arc::new(AtomicU64::new(DEFAULT_OPERATION_LIMIT));
+        let limit = operation_limit.clone();
+        let operations_count = Arc::new(AtomicU64::new(0));
+        let count = operations_count.clone();
+        engine.on_progress(move |operations| {
+            count.store(operations, Ordering::Relaxed);
+            (operations > limit.load(Ordering::Relaxed))
+                .then(|| "Script exceeded operation limit".into())
+        });
+
+        let profiler = Profiler::default();
+        profiler.register(&mut engine);
+
+        // Takes effect from the next run
+        engine.register_fn(
+            "set_operation_limit",
+            move |limit: INT| -> Result<(), Box<EvalAltResult>> {
+                let limit = u64::try_from(limit)
+                    .ok()
+                    .filter(|&limit| limit > 0)
+                    .ok_or_else(|| {
+                        script_error("ArgumentError", "Operation limit must be positive")
+                    })?;
+                operation_limit.store(limit, Ordering::Relaxed);
+                Ok(())
+            },
+        );
+
+        // Warnings and errors go to the host log, and are kept around to show in the UI
+        let log = Arc::new(Mutex::new(VecDeque::new()));
+        for (name, level) in [("log_warn", "Warning"), ("log_error", "Error")] {
+            let log = log.clone();
+            engine.register_fn(name, move |msg: Dynamic| {
+                let line = format!("{}: {}", level, msg);
+                println!("{}", line);
+
+                let mut log = log.lock().unwrap();
+                if log.len() == LOG_CAPACITY {
+                    log.pop_front();
+                }
+                log.push_back(line);
+            });
+        }
+
+        let requests = Arc::new(Mutex::new(ScriptRequests::default()));
+
+        // Returns the new entity's id as a string, matching the keys of state.transforms.
+        // Functions taking an id accept it as a string or as the same number as an integer.
+        // The entity only appears in state.transforms from the next frame onwards.
+        let spawn_requests = requests.clone();
+        engine.register_fn(
+            "spawn_entity",
+            move |transform: Dynamic| -> Result<String, Box<EvalAltResult>> {
+                let transform: Transform = component_from_dynamic(transform)?;
+                transform
+                    .check()
+                    .map_err(|e| script_error("ArgumentError", e))?;
+                let mut requests = spawn_requests.lock().unwrap();
+                let id = requests.reserved.pop().ok_or_else(|| {
+                    script_error(
+                        "EntityError",
+                        "spawn_entity() called too many times this frame",
+                    )
+                })?;
+                requests.spawns.push((id, transform, None));
+                Ok(id.0.to_string())
+            },
+        );
+
+        // Takes the same key used in state.transforms, or the id as an integer
+        let delete_requests = requests.clone();
+        engine.register_fn(
+            "delete_entity",
+            move |id: Dynamic| -> Result<(), Box<EvalAltResult>> {
+                let id = parse_entity_id(&entity_key(&id)?)?;
+                delete_requests.lock().unwrap().deletes.push(id);
+                Ok(())
+            },
+        );
+
+        // Hide and show entities. Entities without a Render drop out of state.transforms, so
+        // keep hold of the id to show it again.
+        for (name, visible) in [("add_render", true), ("remove_render", false)] {
+            let render_requests = requests.clone();
+            engine.register_fn(name, move |id: Dynamic| -> Result<(), Box<EvalAltResult>> {
+                let id = parse_entity_id(&entity_key(&id)?)?;
+                render_requests
+                    .lock()
+                    .unwrap()
+                    .render_changes
+                    .push((id, visible));
+                Ok(())
+            });
+        }
+
+        // Changes go to the server, which merges them (the last write to a key wins) and sends
+        // the result to every client. state.shared only changes once that has happened. Values
+        // are sent as JSON, so they should be plain numbers, strings, arrays and maps.
+        let shared_writes: Arc<Mutex<rhai::Map>> = Default::default();
+        let share_writes = shared_writes.clone();
+        engine.register_fn("share", move |key: &str, value: Dynamic| {
+            share_writes.lock().unwrap().insert(key.into(), value);
+        });
+
+        // Messages for other plugins, sent as ScriptMessage once the script has run. Payloads go
+        // as JSON, so like share() they should hold plain numbers, strings, arrays and maps.
+        let outbox: Arc<Mutex<Vec<ScriptMessage>>> = Default::default();
+        let message_outbox = outbox.clone();
+        engine.register_fn("message", move |tag: &str, payload: rhai::Map| {
+            message_outbox.lock().unwrap().push(ScriptMessage {
+                tag: tag.to_string(),
+                json: format_map_as_json(&payload),
+            });
+        });
+
+        // Keep every entity within a box. Positions outside it are clamped as state.transforms
+        // is written back, and the ids of those entities appear in state.clamped next frame.
+        let bounds: Arc<Mutex<Option<(Vec3, Vec3)>>> = Default::default();
+        let set_bounds = bounds.clone();
+        engine.register_fn(
+            "set_world_bounds",
+            move |min: Array, max: Array| -> Result<(), Box<EvalAltResult>> {
+                *set_bounds.lock().unwrap() = Some(math::to_bounds(&min, &max)?);
+                Ok(())
+            },
+        );
+        let clear_bounds = bounds.clone();
+        engine.register_fn("clear_world_bounds", move || {
+            *clear_bounds.lock().unwrap() = None;
+        });
+
+        // Like spawns, selection changes appear in state.selected from the next frame onwards
+        let selection: Arc<Mutex<Vec<String>>> = Default::default();
+        let select_selection = selection.clone();
+        engine.register_fn(
+            "select",
+            move |id: Dynamic| -> Result<(), Box<EvalAltResult>> {
+                let id = entity_key(&id)?;
+                parse_entity_id(&id)?;
+                let mut selection = select_selection.lock().unwrap();
+                if !selection.contains(&id) {
+                    selection.push(id);
+                }
+                Ok(())
+            },
+        );
+
+        let clear_selection = selection.clone();
+        engine.register_fn("clear_selection", move || {
+            clear_selection.lock().unwrap().clear()
+        });
+
+        // Named groups of entities, e.g. `tag(id, "enemies")` then
+        // `for id in entities_in("enemies") { .. }`. Groups last across frames, and entities drop
+        // out of them once deleted. An entity can be in any number of groups.
+        let groups: Arc<Mutex<HashMap<String, BTreeSet<String>>>> = Default::default();
+        let tag_groups = groups.clone();
+        engine.register_fn(
+            "tag",
+            move |id: Dynamic, group: &str| -> Result<(), Box<EvalAltResult>> {
+                let id = entity_key(&id)?;
+                parse_entity_id(&id)?;
+                tag_groups
+                    .lock()
+                    .unwrap()
+                    .entry(group.to_string())
+                    .or_default()
+                    .insert(id);
+                Ok(())
+            },
+        );
+
+        let untag_groups = groups.clone();
+        engine.register_fn(
+            "untag",
+            move |id: Dynamic, group: &str| -> Result<(), Box<EvalAltResult>> {
+                let id = entity_key(&id)?;
+                let mut groups = untag_groups.lock().unwrap();
+                if let Some(ids) = groups.get_mut(group) {
+                    ids.remove(&id);
+                    if ids.is_empty() {
+                        groups.remove(group);
+                    }
+                }
+                Ok(())
+            },
+        );
+
+        let has_groups = groups.clone();
+        engine.register_fn(
+            "has_tag",
+            move |id: Dynamic, group: &str| -> Result<bool, Box<EvalAltResult>> {
+                let id = entity_key(&id)?;
+                Ok(has_groups
+                    .lock()
+                    .unwrap()
+                    .get(group)
+                    .is_some_and(|ids| ids.contains(&id)))
+            },
+        );
+
+        // Ids in a group, sorted. An unknown group is just empty.
+        let in_groups = groups.clone();
+        engine.register_fn("entities_in", move |group: &str| -> Array {
+            in_groups
+                .lock()
+                .unwrap()
+                .get(group)
+                .into_iter()
+                .flatten()
+                .map(|id| id.into())
+                .collect()
+        });
+
+        // Seconds since the first frame. It's the same throughout a frame, like state.dt.
+        let elapsed: Arc<Mutex<FLOAT>> = Default::default();
+        let now_elapsed = elapsed.clone();
+        engine.register_fn("now", move || *now_elapsed.lock().unwrap());
+
+        // Run a command later, like `after(1.0, "spawn_wave()")`, for timed sequences without
+        // counting frames in `state`. It runs like a command from the command line, once the
+        // time has passed or that many frames have begun. Recompiling the script cancels
+        // anything still waiting, since the script schedules it again as it runs.
+        let deferred: Arc<Mutex<Vec<(Due, String)>>> = Default::default();
+        let frame_count = Arc::new(AtomicU64::new(0));
+        let after_deferred = deferred.clone();
+        let after_elapsed = elapsed.clone();
+        engine.register_fn(
+            "after",
+            move |seconds: FLOAT, command: &str| -> Result<(), Box<EvalAltResult>> {
+                if !(seconds >= 0.0 && seconds.is_finite()) {
+                    return Err(script_error(
+                        "ArgumentError",
+                        "after() needs a delay of zero or more seconds",
+                    ));
+                }
+                let due = *after_elapsed.lock().unwrap() + seconds;
+                after_deferred
+                    .lock()
+                    .unwrap()
+                    .push((Due::Time(due), command.to_string()));
+                Ok(())
+            },
+        );
+        let frames_deferred = deferred.clone();
+        let frames_count = frame_count.clone();
+        engine.register_fn(
+            "after_frames",
+            move |frames: INT, command: &str| -> Result<(), Box<EvalAltResult>> {
+                let frames = u64::try_from(frames).map_err(|_| {
+                    script_error("ArgumentError", "after_frames() needs zero or more frames")
+                })?;
+                let due = frames_count.load(Ordering::Relaxed) + frames;
+                frames_deferred
+                    .lock()
+                    .unwrap()
+                    .push((Due::Frame(due), command.to_string()));
+                Ok(())
+            },
+        );
+
+        // Shorthands for reading state.dt, now() and state.transforms. They're read-only copies,
+        // so writes still go through `state`. Variables the script declares take precedence.
+        let frame_vars: Arc<Mutex<rhai::Map>> = Default::default();
+        let var_values = frame_vars.clone();
+        // on_var() is marked deprecated only to flag that its signature may change
+        #[allow(deprecated)]
+        engine.on_var(move |name, _index, context| {
+            if !FRAME_VARS.contains(&name) || context.scope().contains(name) {
+                return Ok(None);
+            }
+            Ok(var_values.lock().unwrap().get(name).cloned())
+        });
+
+        let transforms: Arc<Mutex<BTreeMap<String, Transform>>> = Default::default();
+
+        // Ids of every entity in state.transforms this frame
+        let id_transforms = transforms.clone();
+        engine.register_fn("entity_ids", move || -> Array {
+            id_transforms
+                .lock()
+                .unwrap()
+                .keys()
+                .map(|id| id.into())
+                .collect()
+        });
+
+        // Whether an entity was alive at the start of this frame, for checking ids kept across
+        // frames before using them. Entities spawned this frame only exist from the next one,
+        // and deleted ones until the end of this one.
+        let exists_transforms = transforms.clone();
+        engine.register_fn(
+            "exists",
+            move |id: Dynamic| -> Result<bool, Box<EvalAltResult>> {
+                let id = entity_key(&id)?;
+                Ok(exists_transforms.lock().unwrap().contains_key(&id))
+            },
+        );
+
+        // Indexing state.transforms with a missing id gives (), which only fails later with a
+        // message about (). These look the id up in this frame's transforms instead; the first
+        // names the missing id in its error, and the second falls back to `default`.
+        let get_transforms = transforms.clone();
+        engine.register_fn(
+            "get_transform",
+            move |id: Dynamic| -> Result<Transform, Box<EvalAltResult>> {
+                find_transform(&get_transforms.lock().unwrap(), &entity_key(&id)?)
+            },
+        );
+
+        let or_transforms = transforms.clone();
+        engine.register_fn(
+            "transform_or",
+            move |id: Dynamic, default: Dynamic| -> Result<Dynamic, Box<EvalAltResult>> {
+                let id = entity_key(&id)?;
+                Ok(match or_transforms.lock().unwrap().get(&id) {
+                    Some(transform) => Dynamic::from(*transform),
+                    None => default,
+                })
+            },
+        );
+
+        // Relationships between two entities, by id. angle_between() compares the directions
+        // they face (-Z), in radians.
+        let distance_transforms = transforms.clone();
+        engine.register_fn(
+            "distance",
+            move |a: Dynamic, b: Dynamic| -> Result<FLOAT, Box<EvalAltResult>> {
+                let transforms = distance_transforms.lock().unwrap();
+                let (a, b) = (
+                    find_transform(&transforms, &entity_key(&a)?)?,
+                    find_transform(&transforms, &entity_key(&b)?)?,
+                );
+                Ok(a.pos.distance(b.pos))
+            },
+        );
+        let angle_transforms = transforms.clone();
+        engine.register_fn(
+            "angle_between",
+            move |a: Dynamic, b: Dynamic| -> Result<FLOAT, Box<EvalAltResult>> {
+                let transforms = angle_transforms.lock().unwrap();
+                let (a, b) = (
+                    find_transform(&transforms, &entity_key(&a)?)?,
+                    find_transform(&transforms, &entity_key(&b)?)?,
+                );
+                Ok((a.orient * Vec3::NEG_Z).angle_between(b.orient * Vec3::NEG_Z))
+            },
+        );
+
+        // Ids of entities within `radius` of `point`
+        let near_transforms = transforms.clone();
+        engine.register_fn(
+            "find_near",
+            move |point: Array, radius: FLOAT| -> Result<Array, Box<EvalAltResult>> {
+                let point = math::to_vec3(&point)?;
+                Ok(near_transforms
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(_, t)| t.pos.distance(point) <= radius)
+                    .map(|(id, _)| id.into())
+                    .collect())
+            },
+        );
+
+        // Ids of entities for which `predicate(id, transform)` returns true
+        let filter_transforms = transforms.clone();
+        engine.register_fn(
+            "filter_transforms",
+            move |context: NativeCallContext,
+                  predicate: FnPtr|
+                  -> Result<Array, Box<EvalAltResult>> {
+                // Copied, so the lock isn't held while calling back into the script
+                let snapshot = filter_transforms.lock().unwrap().clone();
+
+                let mut ids = Array::new();
+                for (id, transform) in snapshot {
+                    let transform = Dynamic::from(transform);
+                    if predicate.call_within_context(&context, (id.clone(), transform))? {
+                        ids.push(id.into());
+                    }
+                }
+                Ok(ids)
+            },
+        );
+
+        // Delete every entity in state.transforms, apart from the ids in `keep`. That includes
+        // entities other plugins created, as long as they have a Transform and a Render.
+        // Entities spawned earlier in the same frame aren't in state.transforms yet, so survive.
+        let clear_transforms = transforms.clone();
+        let clear_requests = requests.clone();
+        let clear_scene = move |keep: Array| {
+            let keep: HashSet<String> = keep.into_iter().map(|id| id.to_string()).collect();
+            let ids: Vec<EntityId> = clear_transforms
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|id| !keep.contains(*id))
+                .filter_map(|id| id.parse().ok().map(EntityId))
+                .collect();
+            clear_requests.lock().unwrap().deletes.extend(ids);
+        };
+        engine.register_fn("clear_scene", clear_scene.clone());
+        engine.register_fn("clear_scene", move || clear_scene(Array::new()));
+
+        // Spawn a copy of an entity, with its transform as of the start of this frame and the same
+        // Render and color. Returns the new id, like spawn_entity().
+        let clone_transforms = transforms.clone();
+        let clone_requests = requests.clone();
+        engine.register_fn(
+            "clone_entity",
+            move |id: Dynamic| -> Result<String, Box<EvalAltResult>> {
+                let id = entity_key(&id)?;
+                let source = parse_entity_id(&id)?;
+                let transform = find_transform(&clone_transforms.lock().unwrap(), &id)?;
+                let mut requests = clone_requests.lock().unwrap();
+                let id = requests.reserved.pop().ok_or_else(|| {
+                    script_error(
+                        "EntityError",
+                        "clone_entity() called too many times this frame",
+                    )
+                })?;
+                requests.spawns.push((id, transform, Some(source)));
+                Ok(id.0.to_string())
+            },
+        );
+
+        // Spread work over several frames, such as `start_job(|| { spawn_entity(..); n -= 1;
+        // n == 0 })`. The function is called once a frame after update(), until it returns
+        // true. Each call counts against the operation limit on its own, so a job should do a
+        // bounded slice of its work per call. Jobs are called without `this`, so they can't see
+        // `state`; closures keep their progress in the variables they capture, which persist
+        // between calls.
+        let started_jobs: Arc<Mutex<Vec<FnPtr>>> = Default::default();
+        let start_jobs = started_jobs.clone();
+        engine.register_fn("start_job", move |function: FnPtr| {
+            start_jobs.lock().unwrap().push(function);
+        });
+
+        let import_requests = requests.clone();
+        engine.register_fn(
+            "import_scene",
+            move |context: NativeCallContext, json: &str| {
+                let transforms = transform::parse_transforms(context.engine(), json)?;
+                import_scene(&import_requests, transforms)
+            },
+        );
+
+        // Builtins are compiled once into a global module. Functions in the user script take
+        // precedence, so it may still override them.
+        let builtins = engine
+            .compile(BUILTIN_SCRIPT)
+            .expect("Builtin script failed to compile");
+        let builtins = Module::eval_ast_as_new(Scope::new(), &builtins, &engine)
+            .expect("Builtin script failed to run");
+        engine.register_global_module(builtins.into());
+
+        let mut runtime = Self {
+            engine,
+            scope: Scope::new(),
+            script: String::new(),
+            ast: AST::empty(),
+            requests,
+            log,
+            prints,
+            watches,
+            key_bindings,
+            started_jobs,
+            jobs: Vec::new(),
+            init_pending: false,
+            deferred,
+            frame_count,
+            transforms,
+            components,
+            copies: HashMap::new(),
+            frame_vars,
+            operations: operations_count,
+            update_operations: 0,
+            profiler,
+            shared: rhai::Map::new(),
+            shared_writes,
+            outbox,
+            ui: rhai::Map::new(),
+            bounds,
+            clamped: Array::new(),
+            hidden: HashMap::new(),
+            colors: HashMap::new(),
+            tinted_cubes: TintedCubes::default(),
+            selection,
+            groups,
+            dt: 0.0,
+            last_frame_time: None,
+            start_time: None,
+            elapsed,
+        };
+
+        if let Err(e) = runtime.compile(script) {
+            println!("Initial script failed: {}", describe_eval_error(e.error()));
+        }
+
+        runtime
+    }
+
+    /// Compile a new script and run its top-level statements once. If it fails to compile the
+    /// previous script and AST are kept, so update() keeps running. Variables declared at the top level only
+    /// last for that run, so recompiling doesn't pile up copies of them in the scope.
+    pub fn compile(&mut self, text: &str) -> Result<(), CompileError> {
+        self.ast = self
+            .engine
+            .compile(text)
+            .map_err(|e| CompileError::Parse(e.into()))?;
+        self.script = text.to_string();
+        // The script binds its keys again as it runs, and old commands may not make sense.
+        // Likewise for jobs, which may be calling functions that no longer exist.
+        self.key_bindings.lock().unwrap().clear();
+        self.jobs.clear();
+        self.deferred.lock().unwrap().clear();
+        self.init_pending = true;
+
+        self.ensure_state();
+        let scope_len = self.scope.len();
+        let result = self.engine.run_ast_with_scope(&mut self.scope, &self.ast);
+        self.scope.rewind(scope_len);
+        adopt_jobs(&self.started_jobs, &mut self.jobs, &self.ast);
+        result.map_err(CompileError::Run)
+    }
+
+    /// Run a command against the compiled script and the current scope. Only the script's
+    /// functions are visible to the command, so any top-level error position is in the command.
+    /// They come from the last script which compiled, so helpers like `fn helper() { 42 }` can
+    /// be called as `helper()` and pick up edits once the script recompiles. Functions defined
+    /// by a command only last for that command.
+    ///
+    /// A command may be several statements, such as `let x = 1; x + 1`. The value of the last
+    /// one is returned, with or without a trailing `;`, and variables declared with `let` stay
+    /// in the scope for later commands.
+    pub fn run_command(&mut self, command: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+        let command_ast = self.engine.compile(command)?;
+        let ast = self.ast.clone_functions_only().merge(&command_ast);
+        let result = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut self.scope, &ast);
+        adopt_jobs(&self.started_jobs, &mut self.jobs, &ast);
+        result
+    }
+
+    /// Call each job started with start_job() once. Jobs which return true are finished, and
+    /// ones which fail are dropped, with their errors returned.
+    pub fn run_jobs(&mut self) -> Vec<EvalAltResult> {
+        let mut errors = vec![];
+        for job in std::mem::take(&mut self.jobs) {
+            let result = job
+                .function
+                .call::<Dynamic>(&self.engine, &job.functions, ())
+                .and_then(|done| {
+                    done.as_bool().map_err(|_| {
+                        script_error(
+                            "TypeError",
+                            format!(
+                                "Job {} returned {}; expected true when done or false to continue",
+                                job.function.fn_name(),
+                                done.type_name()
+                            ),
+                        )
+                    })
+                });
+            // Jobs started by this one need its functions too
+            adopt_jobs(&self.started_jobs, &mut self.jobs, &job.functions);
+            match result {
+                Ok(true) => {}
+                Ok(false) => self.jobs.push(job),
+                Err(e) => errors.push(*e),
+            }
+        }
+        errors
+    }
+
+    /// Number of jobs which haven't finished yet
+    pub fn job_count(&self) -> usize {
+        self.jobs.len()
+    }
+
+    /// Run a command as run_command() does, then undo what it did to the ECS. Edits to the
+    /// components, colors and visibility in `state` are rolled back, and spawns, deletes and
+    /// render changes it requested are dropped. So is anything it set up to change the ECS
+    /// later or elsewhere: jobs, deferred commands, key bindings, shared state writes, messages,
+    /// the selection, groups and world bounds. Anything else it did, like setting variables or
+    /// other entries of `state`, is kept.
+    pub fn dry_run_command(&mut self, command: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+        let mut backup = self.component_fields();
+        if let Some(state) = self
+            .scope
+            .get("state")
+            .and_then(|s| s.read_lock::<rhai::Map>())
+        {
+            for field in ["colors", "visible"] {
+                if let Some(value) = state.get(field) {
+                    backup.push((field, value.clone()));
+                }
+            }
+        }
+        let queued = {
+            let requests = self.requests.lock().unwrap();
+            (
+                requests.spawns.len(),
+                requests.render_changes.len(),
+                requests.deletes.len(),
+            )
+        };
+        let jobs = self.jobs.len();
+        let deferred = self.deferred.lock().unwrap().len();
+        let outbox = self.outbox.lock().unwrap().len();
+        let key_bindings = self.key_bindings.lock().unwrap().clone();
+        let shared_writes = self.shared_writes.lock().unwrap().clone();
+        let selection = self.selection.lock().unwrap().clone();
+        let groups = self.groups.lock().unwrap().clone();
+        let bounds = *self.bounds.lock().unwrap();
+
+        let result = self.run_command(command);
+
+        self.restore_fields(backup);
+        self.jobs.truncate(jobs);
+        self.deferred.lock().unwrap().truncate(deferred);
+        self.outbox.lock().unwrap().truncate(outbox);
+        *self.key_bindings.lock().unwrap() = key_bindings;
+        *self.shared_writes.lock().unwrap() = shared_writes;
+        *self.selection.lock().unwrap() = selection;
+        *self.groups.lock().unwrap() = groups;
+        *self.bounds.lock().unwrap() = bounds;
+        let mut requests = self.requests.lock().unwrap();
+        let (spawns, render_changes, deletes) = queued;
+        // Spawned entities were reserved ahead of time, so their ids can be handed out again
+        let unspawned: Vec<EntityId> = requests.spawns.drain(spawns..).map(|s| s.0).collect();
+        requests.reserved.extend(unspawned);
+        requests.render_changes.truncate(render_changes);
+        requests.deletes.truncate(deletes);
+        result
+    }
+
+    /// Spawn the entities in a scene exported with transforms_to_json(), as import_scene() does
+    /// for scripts. Scripts can only spawn as many entities per frame as were reserved for them,
+    /// but here ids are created for however many the scene has.
+    pub fn import_scene(
+        &self,
+        io: &mut EngineIo,
+        json: &str,
+    ) -> Result<rhai::Map, Box<EvalAltResult>> {
+        let transforms = transform::parse_transforms(&self.engine, json)?;
+        {
+            let mut requests = self.requests.lock().unwrap();
+            while requests.reserved.len() < transforms.len() {
+                requests.reserved.push(io.create_entity().build());
+            }
+        }
+        import_scene(&self.requests, transforms)
+    }
+
+    /// Take the commands scheduled with after() or after_frames() which are now due, in the
+    /// order they were scheduled
+    pub fn take_due_commands(&self) -> Vec<String> {
+        let now = *self.elapsed.lock().unwrap();
+        let frame = self.frame_count.load(Ordering::Relaxed);
+        let mut due = vec![];
+        self.deferred.lock().unwrap().retain(|(when, command)| {
+            let ready = match *when {
+                Due::Time(time) => time <= now,
+                Due::Frame(count) => count <= frame,
+            };
+            if ready {
+                due.push(command.clone());
+            }
+            !ready
+        });
+        due
+    }
+
+    /// Command bound to a key with bind_key(), if any
+    pub fn key_binding(&self, key: &str) -> Option<String> {
+        self.key_bindings.lock().unwrap().get(key).cloned()
+    }
+
+    /// Take the text printed since the last call, one line per print()
+    pub fn take_prints(&self) -> String {
+        std::mem::take(&mut self.prints.lock().unwrap())
+    }
+
+    /// Swap the running script and its scope for `parked`, which is left holding the previous
+    /// ones. Nothing is recompiled, so the script carries on where it left off. Its jobs, key
+    /// bindings and deferred commands go with it, so they don't run while it's parked.
+    pub fn swap_script(&mut self, parked: &mut ParkedScript) {
+        std::mem::swap(&mut self.script, &mut parked.script);
+        std::mem::swap(&mut self.ast, &mut parked.ast);
+        std::mem::swap(&mut self.scope, &mut parked.scope);
+        std::mem::swap(&mut self.jobs, &mut parked.jobs);
+        std::mem::swap(
+            &mut *self.key_bindings.lock().unwrap(),
+            &mut parked.key_bindings,
+        );
+        std::mem::swap(&mut *self.deferred.lock().unwrap(), &mut parked.deferred);
+        self.ensure_state();
+    }
+
+    /// Replace the shared state seen by scripts
+    pub fn set_shared(&mut self, shared: rhai::Map) {
+        self.shared = shared;
+    }
+
+    /// Replace the widget states seen by scripts as state.ui, from the next begin_frame()
+    pub fn set_ui(&mut self, ui: rhai::Map) {
+        self.ui = ui;
+    }
+
+    /// Take the changes to the shared state made since the last call
+    pub fn take_shared_writes(&self) -> rhai::Map {
+        std::mem::take(&mut self.shared_writes.lock().unwrap())
+    }
+
+    /// Take the values watched since the last call, sorted by name
+    pub fn take_watches(&self) -> BTreeMap<String, String> {
+        std::mem::take(&mut self.watches.lock().unwrap())
+    }
+
+    /// The script's own entries in `state` as a JSON map, for restore_state() to put back after
+    /// the plugin reloads. Components and frame data are left out, as are entries holding values
+    /// JSON can't represent, such as functions or transforms. Their keys are returned alongside.
+    pub fn snapshot_state(&self) -> (String, Vec<String>) {
+        let mut snapshot = rhai::Map::new();
+        let mut skipped = vec![];
+        if let Some(state) = self
+            .scope
+            .get("state")
+            .and_then(|s| s.read_lock::<rhai::Map>())
+        {
+            for (key, value) in state.iter() {
+                let key = key.as_str();
+                let is_component = self.components.iter().any(|c| c.field == key);
+                if is_component || FRAME_STATE.contains(&key) {
+                    continue;
+                }
+                if is_json(value) {
+                    snapshot.insert(key.into(), value.clone());
+                } else {
+                    skipped.push(key.to_string());
+                }
+            }
+        }
+        (format_map_as_json(&snapshot), skipped)
+    }
+
+    /// Put entries saved by snapshot_state() back into `state`, replacing any of the same name.
+    /// Returns how many there were.
+    pub fn restore_state(&mut self, json: &str) -> Result<usize, Box<EvalAltResult>> {
+        let snapshot = self.engine.parse_json(json, true)?;
+        self.ensure_state();
+        let mut state = self
+            .scope
+            .get_value::<rhai::Map>("state")
+            .unwrap_or_default();
+        let count = snapshot.len();
+        state.extend(snapshot);
+        self.scope.set_value("state", state);
+        Ok(count)
+    }
+
+    /// Empty the `state` map, dropping everything the script stored in it. Other variables in
+    /// the scope (such as ones declared by commands) are kept. The components and
+    /// frame data are put back into `state` by the next begin_frame().
+    pub fn reset_state(&mut self) {
+        self.scope.set_value("state", rhai::Map::new());
+    }
+
+    /// The variable "state" will always be available
+    fn ensure_state(&mut self) {
+        if self.scope.get("state").is_none() {
+            self.scope.push("state", rhai::Map::new());
+        }
+    }
+
+    /// Run the script's update(), if it has one. If it fails, edits it made to the components in
+    /// `state` are rolled back so a half-finished frame isn't written to the ECS.
+    pub fn run_update(&mut self) -> Result<(), Box<EvalAltResult>> {
+        if !self.defines_fn("update", 0) {
+            return Ok(());
+        }
+
+        let result = self.call_with_state("update");
+        self.update_operations = self.operations.load(Ordering::Relaxed);
+        result
+    }
+
+    /// Run the script's init(), if it has one and hasn't been run since the script compiled.
+    /// Call it each frame before update(), so init() sees that frame's components in `state`
+    /// and runs once per compile. Like update(), a failed init() leaves the components as they
+    /// were.
+    pub fn run_init(&mut self) -> Result<(), Box<EvalAltResult>> {
+        if !std::mem::take(&mut self.init_pending) || !self.defines_fn("init", 0) {
+            return Ok(());
+        }
+        self.call_with_state("init")
+    }
+
+    /// Run the script's update_entity(id) for each selected entity, instead of update(). The
+    /// entity's transform is bound to `this`, since Rhai functions can't see variables outside
+    /// them, and edits to it are written back to state.transforms. If any call fails, none of
+    /// the edits are kept.
+    pub fn run_entity_updates(&mut self) -> Result<(), Box<EvalAltResult>> {
+        if !self.defines_fn("update_entity", 1) {
+            return Ok(());
+        }
+
+        self.ensure_state();
+        let Some(mut state) = self.scope.get_value::<rhai::Map>("state") else {
+            return Ok(());
+        };
+        let Some(mut transforms) = state
+            .get("transforms")
+            .and_then(|transforms| transforms.clone().try_cast::<rhai::Map>())
+        else {
+            return Ok(());
+        };
+
+        let ids = self.selection.lock().unwrap().clone();
+        self.update_operations = 0;
+        for id in ids {
+            // Selected entities which haven't appeared in state.transforms yet are skipped
+            let Some(transform) = transforms.get_mut(id.as_str()) else {
+                continue;
+            };
+
+            let options = CallFnOptions::new()
+                .eval_ast(false)
+                .bind_this_ptr(transform);
+            self.operations.store(0, Ordering::Relaxed);
+            let result = self
+                .engine
+                .call_fn_with_options::<Dynamic>(
+                    options,
+                    &mut self.scope,
+                    &self.ast,
+                    "update_entity",
+                    (id,),
+                )
+                .map(|_| ());
+            self.update_operations += self.operations.load(Ordering::Relaxed);
+            adopt_jobs(&self.started_jobs, &mut self.jobs, &self.ast);
+            result?;
+        }
+
+        state.insert("transforms".into(), transforms.into());
+        self.scope.set_value("state", state);
+        Ok(())
+    }
+
+    /// Call a script function like update() with `state` as `this`, rolling back its edits to
+    /// the components if it fails. Unlike run_command(), nothing is parsed and the return value
+    /// is dropped straight away, since this happens every frame.
+    fn call_with_state(&mut self, name: &str) -> Result<(), Box<EvalAltResult>> {
+        let backup = self.component_fields();
+        self.ensure_state();
+        let mut state = self.scope.remove::<Dynamic>("state").unwrap_or_default();
+
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut state);
+        self.operations.store(0, Ordering::Relaxed);
+        let result = self
+            .engine
+            .call_fn_with_options::<Dynamic>(options, &mut self.scope, &self.ast, name, ())
+            .map(|_| ());
+        adopt_jobs(&self.started_jobs, &mut self.jobs, &self.ast);
+
+        self.scope.set_value("state", state);
+        if result.is_err() {
+            self.restore_fields(backup);
+        }
+        result
+    }
+
+    /// Seconds since the first frame, as returned to scripts by now()
+    pub fn time(&self) -> FLOAT {
+        *self.elapsed.lock().unwrap()
+    }
+
+    /// Seconds since the previous frame, as of the last begin_frame()
+    pub fn dt(&self) -> FLOAT {
+        self.dt
+    }
+
+    /// Override state.dt (and the `dt` shorthand) for the rest of the frame, such as for
+    /// running update() in fixed steps
+    pub fn set_dt(&mut self, dt: FLOAT) {
+        if let Some(mut state) = self
+            .scope
+            .get_mut("state")
+            .and_then(|s| s.write_lock::<rhai::Map>())
+        {
+            state.insert("dt".into(), Dynamic::from_float(dt));
+        }
+        self.frame_vars
+            .lock()
+            .unwrap()
+            .insert("dt".into(), Dynamic::from_float(dt));
+    }
+
+    /// Operations the last update() took. Wall-clock timers aren't available to the plugin,
+    /// but this is a deterministic measure of how much work a script does each frame.
+    pub fn update_operations(&self) -> u64 {
+        self.update_operations
+    }
+
+    /// Copies of the component fields currently in `state`
+    fn component_fields(&self) -> Vec<(&'static str, Dynamic)> {
+        let Some(state) = self
+            .scope
+            .get("state")
+            .and_then(|s| s.read_lock::<rhai::Map>())
+        else {
+            return vec![];
+        };
+
+        self.components
+            .iter()
+            .filter_map(|component| Some((component.field, state.get(component.field)?.clone())))
+            .collect()
+    }
+
+    /// Warnings about the compiled script which don't stop it running, but are likely mistakes
+    pub fn lint(&self) -> Vec<String> {
+        if self.ast.statements().is_empty() && self.ast.iter_functions().next().is_none() {
+            return vec!["The script is empty; define fn update() to run code each frame".into()];
+        }
+
+        let mut warnings = vec![];
+        if !self.defines_fn("update", 0) && !self.defines_fn("update_entity", 1) {
+            warnings.push(
+                "No update() function, so nothing runs after setup; \
+                 define fn update() to run code each frame"
+                    .into(),
+            );
+        }
+        warnings
+    }
+
+    /// Put back fields of `state` copied by component_fields()
+    fn restore_fields(&mut self, backup: Vec<(&'static str, Dynamic)>) {
+        if let Some(mut state) = self.scope.get_value::<rhai::Map>("state") {
+            for (field, value) in backup {
+                state.insert(field.into(), value);
+            }
+            self.scope.set_value("state", state);
+        }
+    }
+
+    /// Whether the script defines a function `name` taking no arguments
+    pub fn defines_fn(&self, name: &str, params: usize) -> bool {
+        self.ast
+            .iter_functions()
+            .any(|f| f.name == name && f.params.len() == params)
+    }
+
+    /// Copy this frame's ECS data into `state`, ready for scripts to run
+    pub fn begin_frame(&mut self, io: &mut EngineIo, query: &mut QueryResult) {
+        self.ensure_state();
+        self.frame_count.fetch_add(1, Ordering::Relaxed);
+
+        self.reserve_entities(io);
+
+        // Seconds since the previous frame; zero on the first frame
+        let dt = match io.inbox::<FrameTime>().last() {
+            Some(frame) => {
+                let dt = self
+                    .last_frame_time
+                    .map_or(0.0, |last| (frame.time - last).clamp(0.0, MAX_DT));
+                self.last_frame_time = Some(frame.time);
+
+                // Never goes backwards, even if the engine's clock does
+                let start = *self.start_time.get_or_insert(frame.time);
+                let mut elapsed = self.elapsed.lock().unwrap();
+                *elapsed = elapsed.max(frame.time - start);
+
+                dt
+            }
+            None => 0.0,
+        };
+        self.dt = dt;
+
+        // Copy ECS data into rhai. Every component field is present, as an empty map if the
+        // world has no entities yet.
+        let ids: Vec<EntityId> = query.iter("Transforms").collect();
+        let components: Vec<(&str, Dynamic)> = self
+            .components
+            .iter()
+            .map(|component| {
+                let copies = self.copies.entry(component.field).or_default();
+                let map = (component.read)(query, &ids, copies);
+                (component.field, map.into())
+            })
+            .collect();
+
+        let map: BTreeMap<String, Transform> = ids
+            .iter()
+            .map(|&id| (id.0.to_string(), query.read::<Transform>(id)))
+            .collect();
+        let entity_count = map.len() as INT;
+
+        // Colors set by the script, minus those of deleted entities
+        self.colors.retain(|id, _| map.contains_key(id));
+        let colors: rhai::Map = self
+            .colors
+            .iter()
+            .map(|(id, &color)| (id.into(), color::from_color(color).into()))
+            .collect();
+
+        // Every entity the script can show or hide: those in the query, and those hidden with
+        // remove_render() or state.visible, which have dropped out of it
+        let visible: rhai::Map = map
+            .keys()
+            .map(|id| (id.into(), true.into()))
+            .chain(
+                self.hidden
+                    .keys()
+                    .map(|id| (id.0.to_string().into(), false.into())),
+            )
+            .collect();
+
+        // Forget selected entities which have been deleted
+        let mut selection = self.selection.lock().unwrap();
+        selection.retain(|id| map.contains_key(id));
+        let selected: Array = selection.iter().map(|id| id.into()).collect();
+        drop(selection);
+
+        // Likewise for tagged entities, dropping groups left empty
+        self.groups.lock().unwrap().retain(|_, ids| {
+            ids.retain(|id| map.contains_key(id));
+            !ids.is_empty()
+        });
+
+        // Transforms from the previous frame, for working out velocities. Entities which are
+        // new this frame (including every entity on the first frame) get their current one.
+        let prev_transforms: rhai::Map = {
+            let mut snapshot = self.transforms.lock().unwrap();
+            let prev_transforms = map
+                .iter()
+                .map(|(id, current)| {
+                    let transform = snapshot.get(id).unwrap_or(current);
+                    (id.into(), Dynamic::from(*transform))
+                })
+                .collect();
+            *snapshot = map;
+            prev_transforms
+        };
+
+        // TODO: Just how slow is this?
+        if let Some(mut state) = self.scope.remove::<rhai::Map>("state") {
+            for (field, value) in components {
+                state.insert(field.into(), value);
+            }
+            state.insert("dt".into(), Dynamic::from_float(dt));
+
+            let mut frame_vars = self.frame_vars.lock().unwrap();
+            frame_vars.insert("dt".into(), Dynamic::from_float(dt));
+            frame_vars.insert("time".into(), (*self.elapsed.lock().unwrap()).into());
+            // Shared, so each read of `transforms` is a cheap clone of the handle rather than of
+            // every transform
+            if let Some(transforms) = state.get("transforms") {
+                let transforms = transforms.clone().into_read_only().into_shared();
+                frame_vars.insert("transforms".into(), transforms);
+            }
+            drop(frame_vars);
+
+            state.insert("entity_count".into(), Dynamic::from_int(entity_count));
+            state.insert("selected".into(), selected.into());
+            state.insert("shared".into(), self.shared.clone().into());
+            state.insert("ui".into(), self.ui.clone().into());
+            state.insert("clamped".into(), self.clamped.clone().into());
+            state.insert("messages".into(), self.read_messages(io).into());
+            let mut perf = rhai::Map::new();
+            perf.insert(
+                "update_operations".into(),
+                Dynamic::from_int(self.update_operations as INT),
+            );
+            state.insert("perf".into(), perf.into());
+            state.insert("colors".into(), colors.into());
+            state.insert("visible".into(), visible.into());
+            state.insert("prev_transforms".into(), prev_transforms.into());
+            self.scope.set_value("state", state);
+        }
+    }
+
+    /// Copy `state` back into the ECS, then apply any spawns and deletes. Returns a message for
+    /// each entry of `state` which couldn't be written back.
+    pub fn end_frame(&mut self, io: &mut EngineIo, query: &mut QueryResult) -> Vec<String> {
+        let mut errors = vec![];
+
+        // Copy ECS data back into cimvr
+        if let Some(mut state) = self.scope.remove::<rhai::Map>("state") {
+            self.clamped = match *self.bounds.lock().unwrap() {
+                Some(bounds) => clamp_transforms(&mut state, bounds),
+                None => Array::new(),
+            };
+
+            let no_copies = Copies::new();
+            for component in &self.components {
+                let Some(value) = state.remove(component.field) else {
+                    continue;
+                };
+                if component.access == Access::Write {
+                    let copies = self.copies.get(component.field).unwrap_or(&no_copies);
+                    errors.extend((component.write)(query, component.field, value, copies));
+                }
+            }
+
+            // After state.renders, so a new color isn't overwritten by the old mesh
+            if let Some(colors) = state.remove("colors") {
+                errors.extend(self.write_colors(io, query, colors));
+            }
+
+            if let Some(visible) = state.remove("visible") {
+                errors.extend(self.write_visible(visible));
+            }
+
+            self.scope.set_value("state", state);
+        }
+
+        // Spawns and deletes happen last, so they're never visible to the write-back above
+        self.apply_requests(io, query);
+
+        for message in self.outbox.lock().unwrap().drain(..) {
+            io.send(&message);
+        }
+
+        errors
+    }
+
+    /// Messages received since the last frame, as `#{tag, payload}` maps for state.messages.
+    /// That includes the script's own messages from last frame. Payloads which aren't valid
+    /// JSON maps are dropped.
+    fn read_messages(&self, io: &mut EngineIo) -> Array {
+        io.inbox::<ScriptMessage>()
+            .filter_map(|message| {
+                let payload = match self.engine.parse_json(&message.json, true) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        println!("Bad payload in message {}: {}", message.tag, e);
+                        return None;
+                    }
+                };
+                let mut map = rhai::Map::new();
+                map.insert("tag".into(), message.tag.into());
+                map.insert("payload".into(), payload.into());
+                Some(map.into())
+            })
+            .collect()
+    }
+
+    /// Tint each entity in state.colors by pointing its Render at a cube of that color. Only
+    /// changed colors touch the ECS, and only cubes can be tinted. Removing an entry leaves the
+    /// entity's color as it is.
+    fn write_colors(
+        &mut self,
+        io: &mut EngineIo,
+        query: &mut QueryResult,
+        colors: Dynamic,
+    ) -> Vec<String> {
+        let Some(colors) = colors.try_cast::<rhai::Map>() else {
+            return vec!["Error: state.colors must be a map".into()];
+        };
+
+        let alive = self.transforms.lock().unwrap();
+        let mut errors = vec![];
+        let mut written = HashMap::new();
+        for (key, value) in colors {
+            let color = value
+                .try_cast::<Array>()
+                .ok_or_else(|| script_error("TypeError", "Expected a color array"))
+                .and_then(|array| color::to_color(&array));
+            let color = match color {
+                Ok(_) if !alive.contains_key(key.as_str()) => {
+                    errors.push(format!(
+                        "Error: state.colors has unknown entity \"{}\"",
+                        key
+                    ));
+                    continue;
+                }
+                Ok(color) => color,
+                Err(e) => {
+                    let e = describe_eval_error(&e);
+                    errors.push(format!("Error in state.colors[\"{}\"]: {}", key, e));
+                    continue;
+                }
+            };
+
+            if self.colors.get(key.as_str()) != Some(&color) {
+                if let Ok(id) = parse_entity_id(&key) {
+                    let mut render = query.read::<Render>(id);
+                    // Any other mesh would be replaced by a cube
+                    if !self.tinted_cubes.is_cube(render.id) {
+                        errors.push(format!(
+                            "Error in state.colors[\"{}\"]: only cubes can be colored",
+                            key
+                        ));
+                        continue;
+                    }
+                    render.id = self.tinted_cubes.handle(io, color);
+                    query.write(id, &render);
+                }
+            }
+            written.insert(key.to_string(), color);
+        }
+
+        drop(alive);
+        self.colors = written;
+        errors
+    }
+
+    /// Hide or show each entity whose entry in state.visible changed, as remove_render() and
+    /// add_render() do. Removing an entry leaves the entity as it is.
+    fn write_visible(&self, visible: Dynamic) -> Vec<String> {
+        let Some(visible) = visible.try_cast::<rhai::Map>() else {
+            return vec!["Error: state.visible must be a map".into()];
+        };
+
+        let shown = self.transforms.lock().unwrap();
+        let mut requests = self.requests.lock().unwrap();
+        let mut errors = vec![];
+        for (key, value) in visible {
+            let Ok(visible) = value.as_bool() else {
+                errors.push(format!(
+                    "Error in state.visible[\"{}\"]: expected true or false",
+                    key
+                ));
+                continue;
+            };
+            let was_visible = shown.contains_key(key.as_str());
+            let id = match parse_entity_id(&key) {
+                Ok(id) if was_visible || self.hidden.contains_key(&id) => id,
+                _ => {
+                    errors.push(format!(
+                        "Error: state.visible has unknown entity \"{}\"",
+                        key
+                    ));
+                    continue;
+                }
+            };
+            if visible != was_visible {
+                requests.render_changes.push((id, visible));
+            }
+        }
+        errors
+    }
+
+    /// Top up the pool of entity ids handed out by spawn_entity()
+    fn reserve_entities(&mut self, io: &mut EngineIo) {
+        let mut requests = self.requests.lock().unwrap();
+        while requests.reserved.len() < MAX_SPAWNS_PER_FRAME {
+            requests.reserved.push(io.create_entity().build());
+        }
+    }
+
+    /// Apply the ECS changes queued by native functions during this frame's script run
+    fn apply_requests(&mut self, io: &mut EngineIo, query: &mut QueryResult) {
+        let mut requests = self.requests.lock().unwrap();
+        let in_query: HashSet<EntityId> = query.iter("Transforms").collect();
+        let mut alive = in_query.clone();
+        let spawn_render = Render::new(CUBE_HANDLE).primitive(Primitive::Triangles);
+
+        for (id, transform, source) in requests.spawns.drain(..) {
+            // The source may have been hidden since clone_entity() was called
+            let render = match source {
+                Some(source) if in_query.contains(&source) => query.read::<Render>(source),
+                Some(source) => self.hidden.get(&source).copied().unwrap_or(spawn_render),
+                None => spawn_render,
+            };
+            if let Some(color) = source.and_then(|source| self.colors.get(&source.0.to_string())) {
+                self.colors.insert(id.0.to_string(), *color);
+            }
+            io.add_component(id, transform);
+            io.add_component(id, render);
+            alive.insert(id);
+        }
+
+        // Entities which already have (or lack) a Render are skipped, as are ids add_render()
+        // doesn't know, since it can't tell if they're still alive
+        for (id, visible) in requests.render_changes.drain(..) {
+            if visible {
+                if let Some(render) = self.hidden.remove(&id) {
+                    io.add_component(id, render);
+                    alive.insert(id);
+                }
+            } else if alive.remove(&id) {
+                // Entities spawned this frame aren't in the query yet
+                let render = if in_query.contains(&id) {
+                    query.read::<Render>(id)
+                } else {
+                    spawn_render
+                };
+                io.remove_component::<Render>(id);
+                self.hidden.insert(id, render);
+            }
+        }
+
+        // Ids which are already gone (or were deleted twice) are skipped
+        for id in requests.deletes.drain(..) {
+            if alive.remove(&id) || self.hidden.remove(&id).is_some() {
+                io.remove_entity(id);
+            }
+        }
+    }
+}
+
+/// Whether `value` can be written as JSON and read back as the same value
+fn is_json(value: &Dynamic) -> bool {
+    if let Some(array) = value.read_lock::<Array>() {
+        array.iter().all(is_json)
+    } else if let Some(map) = value.read_lock::<rhai::Map>() {
+        map.values().all(is_json)
+    } else {
+        // NaN and infinity aren't valid JSON numbers
+        value.is_unit()
+            || value.is_bool()
+            || value.is_int()
+            || value.as_float().is_ok_and(FLOAT::is_finite)
+            || value.is_string()
+    }
+}
+
+/// Move the jobs started while running `ast` into `jobs`, along with its functions
+fn adopt_jobs(started: &Mutex<Vec<FnPtr>>, jobs: &mut Vec<Job>, ast: &AST) {
+    let started = std::mem::take(&mut *started.lock().unwrap());
+    jobs.extend(started.into_iter().map(|function| Job {
+        function,
+        functions: ast.clone_functions_only(),
+    }));
+}
+
+/// Spawn a new entity for each of `transforms`, parsed from transforms_to_json(), and return a
+/// map from each exported id to its new one. Ids can't be chosen when creating entities, so
+/// they're always remapped; the scene is added alongside whatever already exists. Either every
+/// entity is spawned or, if there aren't enough reserved ids, none are.
+fn import_scene(
+    requests: &Mutex<ScriptRequests>,
+    transforms: BTreeMap<String, Transform>,
+) -> Result<rhai::Map, Box<EvalAltResult>> {
+    let mut requests = requests.lock().unwrap();
+    if transforms.len() > requests.reserved.len() {
+        return Err(script_error(
+            "EntityError",
+            format!(
+                "Can't spawn {} entities this frame, only {} more",
+                transforms.len(),
+                requests.reserved.len()
+            ),
+        ));
+    }
+
+    let mut ids = rhai::Map::new();
+    for (old_id, t) in transforms {
+        let id = requests
+            .reserved
+            .pop()
+            .expect("Checked there were enough ids");
+        requests.spawns.push((id, t, None));
+        ids.insert(old_id.into(), id.0.to_string().into());
+    }
+    Ok(ids)
+}
+
+/// Append a line of text, dropping the oldest lines once over `capacity` bytes
+pub fn push_line(buffer: &mut String, line: &str, capacity: usize) {
+    buffer.push_str(line);
+    buffer.push('\n');
+
+    if buffer.len() > capacity {
+        let mut cut = buffer.len() - capacity;
+        while !buffer.is_char_boundary(cut) {
+            cut += 1;
+        }
+        // Drop whole lines, so the buffer never starts partway through one
+        let end = buffer[cut..]
+            .find('\n')
+            .map_or(buffer.len(), |i| cut + i + 1);
+        buffer.drain(..end);
+    }
+}
+
+/// A component as copied into `state` at the start of the frame
+struct Copied {
+    /// The component itself, as its native type
+    component: Dynamic,
+    /// What scripts were given, which is the same as `component` for native types
+    value: Dynamic,
+}
+
+/// Components of one type as copied into `state` this frame, by entity. Only entities in this
+/// frame's query are here.
+type Copies = HashMap<EntityId, Copied>;
+
+/// Where components are read from and written to; the query, except in tests
+trait ComponentStore {
+    fn read<C: Component + Clone + Send + Sync + 'static>(&self, id: EntityId) -> C;
+    fn write<C: Component + Clone + Send + Sync + 'static>(&mut self, id: EntityId, component: &C);
+}
+
+impl ComponentStore for QueryResult {
+    fn read<C: Component + Clone + Send + Sync + 'static>(&self, id: EntityId) -> C {
+        QueryResult::read::<C>(self, id)
+    }
+
+    fn write<C: Component + Clone + Send + Sync + 'static>(&mut self, id: EntityId, component: &C) {
+        QueryResult::write(self, id, component)
+    }
+}
+
+/// A component type scripts can see, as a map from entity id to component in `state`
+#[derive(Clone)]
+pub struct ComponentField {
+    /// Field of `state` holding the components
+    pub field: &'static str,
+    /// With Access::Read, scripts still see the components, but edits to them are dropped
+    access: Access,
+    /// The component's type
+    component: TypeId,
+    intersect: fn(Query, Access) -> Query,
+    read: fn(&mut QueryResult, &[EntityId], &mut Copies) -> rhai::Map,
+    write: fn(&mut QueryResult, &str, Dynamic, &Copies) -> Vec<String>,
+}
+
+impl ComponentField {
+    /// A component which scripts see as a native type. The type should be registered with the
+    /// engine, or scripts won't be able to do much with it.
+    pub fn native<
+        C: Component + DeserializeOwned + PartialEq + CheckComponent + Clone + Send + Sync + 'static,
+    >(
+        field: &'static str,
+        access: Access,
+    ) -> Self {
+        Self {
+            field,
+            access,
+            component: TypeId::of::<C>(),
+            intersect: |query, access| query.intersect::<C>(access),
+            read: |query, ids, copies| {
+                read_components::<C>(query, ids, copies, |c| Dynamic::from(*c))
+            },
+            write: write_components::<C, QueryResult>,
+        }
+    }
+
+    /// A component which scripts see as maps and arrays, converted with serde
+    pub fn serde<
+        C: Component
+            + Serialize
+            + DeserializeOwned
+            + PartialEq
+            + CheckComponent
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+    >(
+        field: &'static str,
+        access: Access,
+    ) -> Self {
+        Self {
+            field,
+            access,
+            component: TypeId::of::<C>(),
+            intersect: |query, access| query.intersect::<C>(access),
+            read: |query, ids, copies| {
+                read_components::<C>(query, ids, copies, |c| rhai::serde::to_dynamic(c).unwrap())
+            },
+            write: write_components::<C, QueryResult>,
+        }
+    }
+}
+
+/// Read the components of `ids` into a map for `state`, converting each with `to_value` and
+/// keeping what was copied in `copies`. Components which are the same as last frame reuse last
+/// frame's conversion instead.
+fn read_components<C: Component + PartialEq + Clone + Send + Sync + 'static>(
+    query: &mut impl ComponentStore,
+    ids: &[EntityId],
+    copies: &mut Copies,
+    to_value: fn(&C) -> Dynamic,
+) -> rhai::Map {
+    let mut previous = std::mem::take(copies);
+    ids.iter()
+        .map(|&id| {
+            let component = query.read::<C>(id);
+            let copied = match previous.remove(&id) {
+                Some(copied)
+                    if copied
+                        .component
+                        .read_lock::<C>()
+                        .is_some_and(|c| *c == component) =>
+                {
+                    copied
+                }
+                _ => Copied {
+                    value: to_value(&component),
+                    component: Dynamic::from(component),
+                },
+            };
+            let value = copied.value.clone();
+            copies.insert(id, copied);
+            (id.0.to_string().into(), value)
+        })
+        .collect()
+}
+
+/// Write components edited by a script back into the ECS. Entries the script didn't change from
+/// `copies` are skipped without being converted back. Entries with a malformed key or value, or
+/// for an entity which isn't in `copies` (this frame's query), are skipped too, and a message
+/// describing each of them is returned instead.
+fn write_components<
+    C: Component + DeserializeOwned + PartialEq + CheckComponent + Clone + Send + Sync + 'static,
+    S: ComponentStore,
+>(
+    query: &mut S,
+    field: &str,
+    map: Dynamic,
+    copies: &Copies,
+) -> Vec<String> {
+    let Some(map) = map.try_cast::<rhai::Map>() else {
+        return vec![format!("Error: state.{} must be a map", field)];
+    };
+
+    let mut errors = vec![];
+    for (key, value) in map {
+        let Ok(num) = key.parse() else {
+            errors.push(format!(
+                "Error: state.{} has non-numeric key \"{}\"",
+                field, key
+            ));
+            continue;
+        };
+
+        // Such as one spawned this frame, a stale id, or a typo. The query can't be read or
+        // written for entities outside it.
+        let Some(copied) = copies.get(&EntityId(num)) else {
+            errors.push(format!(
+                "Error: state.{} has unknown entity \"{}\"",
+                field, key
+            ));
+            continue;
+        };
+
+        // Most scripts leave most entities alone, and comparing is cheaper than converting
+        let unchanged = match (value.read_lock::<C>(), copied.component.read_lock::<C>()) {
+            (Some(value), Some(component)) => *value == *component,
+            _ => same_value(&value, &copied.value),
+        };
+        if unchanged {
+            continue;
+        }
+
+        let component = match component_from_dynamic::<C>(value) {
+            Ok(component) => component,
+            Err(e) => {
+                errors.push(format!("Error in state.{}[\"{}\"]: {}", field, key, e));
+                continue;
+            }
+        };
+
+        // A bad value would corrupt the scene for everyone, so leave the entity as it was
+        if let Err(e) = component.check() {
+            errors.push(format!("Error in state.{}[\"{}\"]: {}", field, key, e));
+            continue;
+        }
+
+        query.write(EntityId(num), &component);
+    }
+
+    errors
+}
+
+/// Whether two script values are equal, comparing plain values, arrays and maps. Values of other
+/// types are never equal, so they're always written back.
+fn same_value(a: &Dynamic, b: &Dynamic) -> bool {
+    if let (Some(a), Some(b)) = (a.read_lock::<rhai::Map>(), b.read_lock::<rhai::Map>()) {
+        a.len() == b.len()
+            && a.iter()
+                .zip(b.iter())
+                .all(|((ka, va), (kb, vb))| ka == kb && same_value(va, vb))
+    } else if let (Some(a), Some(b)) = (a.read_lock::<Array>(), b.read_lock::<Array>()) {
+        a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| same_value(a, b))
+    } else if let (Ok(a), Ok(b)) = (a.as_int(), b.as_int()) {
+        a == b
+    } else if let (Ok(a), Ok(b)) = (a.as_float(), b.as_float()) {
+        a == b
+    } else if let (Ok(a), Ok(b)) = (a.as_bool(), b.as_bool()) {
+        a == b
+    } else if let (Some(a), Some(b)) = (
+        a.read_lock::<ImmutableString>(),
+        b.read_lock::<ImmutableString>(),
+    ) {
+        *a == *b
+    } else {
+        a.is_unit() && b.is_unit()
+    }
+}
+
+/// Sanity checks on a component before it's written to the ECS. Components exposed with
+/// ComponentField need an implementation, which may be empty to accept every value.
+pub trait CheckComponent {
+    fn check(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl CheckComponent for Transform {
+    fn check(&self) -> Result<(), String> {
+        if !self.pos.is_finite() {
+            Err(format!("Position {} isn't finite", self.pos))
+        } else if !self.orient.is_finite() {
+            Err(format!("Orientation {} isn't finite", self.orient))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl CheckComponent for Render {}
+
+/// Move entities in state.transforms inside `bounds`, returning the ids of those which moved.
+/// Entries which aren't transforms are left for the write-back to report.
+fn clamp_transforms(state: &mut rhai::Map, (min, max): (Vec3, Vec3)) -> Array {
+    let Some(mut transforms) = state
+        .get_mut("transforms")
+        .and_then(|transforms| transforms.write_lock::<rhai::Map>())
+    else {
+        return Array::new();
+    };
+
+    let mut clamped = Array::new();
+    for (id, value) in transforms.iter_mut() {
+        // Non-finite positions are rejected by the write-back instead
+        if let Some(mut t) = value
+            .write_lock::<Transform>()
+            .filter(|t| t.pos.is_finite())
+        {
+            let pos = t.pos.clamp(min, max);
+            if pos != t.pos {
+                t.pos = pos;
+                clamped.push(id.to_string().into());
+            }
+        }
+    }
+    clamped
+}
+
+/// Look up an entity in this frame's transforms, with an error naming the id if it's missing
+fn find_transform(
+    transforms: &BTreeMap<String, Transform>,
+    id: &str,
+) -> Result<Transform, Box<EvalAltResult>> {
+    transforms.get(id).copied().ok_or_else(|| {
+        script_error(
+            "EntityError",
+            format!("No entity \"{}\" in state.transforms", id),
+        )
+    })
+}
+
+/// Convert a script value into a component, either because it's already the native type or by
+/// deserializing it from a map
+pub fn component_from_dynamic<C: DeserializeOwned + Clone + Send + Sync + 'static>(
+    value: Dynamic,
+) -> Result<C, Box<EvalAltResult>> {
+    if value.is::<C>() {
+        Ok(value.cast::<C>())
+    } else {
+        rhai::serde::from_dynamic(&value)
+    }
+}
+
+/// An error scripts can catch with `try`/`catch`. Like Rhai's own errors, the caught value is a
+/// map with the kind of error in `error` and a description in `message`.
+pub fn script_error(kind: &str, message: impl Into<String>) -> Box<EvalAltResult> {
+    let mut error = rhai::Map::new();
+    error.insert("error".into(), kind.into());
+    error.insert("message".into(), message.into().into());
+    EvalAltResult::ErrorRuntime(error.into(), Position::NONE).into()
+}
+
+/// "kind: message" for an error thrown by script_error(), or a script throwing a map like it
+fn thrown_message(value: &Dynamic) -> Option<String> {
+    let error = value.read_lock::<rhai::Map>()?;
+    Some(format!(
+        "{}: {}",
+        error.get("error")?,
+        error.get("message")?
+    ))
+}
+
+/// Why ScriptRuntime::compile() failed
+#[derive(Debug)]
+pub enum CompileError {
+    /// The script didn't parse, so the previous one is still running
+    Parse(Box<EvalAltResult>),
+    /// The script replaced the previous one, but its top-level statements failed partway
+    Run(Box<EvalAltResult>),
+}
+
+impl CompileError {
+    pub fn error(&self) -> &EvalAltResult {
+        match self {
+            Self::Parse(e) | Self::Run(e) => e,
+        }
+    }
+}
+
+/// A failed script run by category, for callers which handle some kinds of error differently.
+/// Each variant holds a description of the error to show the user.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptError {
+    /// The text didn't parse
+    Compile(String),
+    /// Aborted for going over the operation limit
+    Timeout(String),
+    /// A value had the wrong type, including TypeErrors thrown by native functions
+    TypeMismatch(String),
+    /// Anything else which went wrong while running
+    Runtime(String),
+}
+
+impl ScriptError {
+    /// Categorize `e`, keeping `description` as the message
+    pub fn new(e: &EvalAltResult, description: String) -> Self {
+        match e {
+            EvalAltResult::ErrorInFunctionCall(_, _, inner, _) => Self::new(inner, description),
+            EvalAltResult::ErrorParsing(..) => Self::Compile(description),
+            EvalAltResult::ErrorTerminated(..) | EvalAltResult::ErrorTooManyOperations(_) => {
+                Self::Timeout(description)
+            }
+            EvalAltResult::ErrorMismatchDataType(..)
+            | EvalAltResult::ErrorMismatchOutputType(..) => Self::TypeMismatch(description),
+            EvalAltResult::ErrorRuntime(value, _)
+                if value
+                    .read_lock::<rhai::Map>()
+                    .and_then(|error| error.get("error").map(|kind| kind.to_string()))
+                    .as_deref()
+                    == Some("TypeError") =>
+            {
+                Self::TypeMismatch(description)
+            }
+            _ => Self::Runtime(description),
+        }
+    }
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Compile(description) => write!(f, "syntax error, {}", description),
+            Self::Timeout(description)
+            | Self::TypeMismatch(description)
+            | Self::Runtime(description) => f.write_str(description),
+        }
+    }
+}
+
+/// Describe an error as "line N, col M: message". The script, builtins and each command are
+/// compiled separately, so positions are relative to whichever of those the error is in.
+/// Errors inside functions are reported as "in name(), line N, col M: message".
+pub fn describe_eval_error(e: &EvalAltResult) -> String {
+    match e {
+        // Point inside the function which failed, rather than at the call site
+        EvalAltResult::ErrorInFunctionCall(name, _, inner, _) => match **inner {
+            EvalAltResult::ErrorInFunctionCall(..) => describe_eval_error(inner),
+            _ => format!("in {}(), {}", name, describe_eval_error(inner)),
+        },
+        EvalAltResult::ErrorRuntime(value, pos) if thrown_message(value).is_some() => {
+            with_position(*pos, thrown_message(value).unwrap_or_default())
+        }
+        EvalAltResult::ErrorTerminated(token, pos) => with_position(*pos, token),
+        EvalAltResult::ErrorStackOverflow(pos) => with_position(
+            *pos,
+            format!("Recursion too deep, over {} nested calls", MAX_CALL_LEVELS),
+        ),
+        EvalAltResult::ErrorParsing(ParseErrorType::ExprTooDeep, pos) => with_position(
+            *pos,
+            format!(
+                "Expression nested too deeply, over {} levels",
+                MAX_EXPR_DEPTH
+            ),
+        ),
+        _ => {
+            // Rhai appends the position to the message, which we'd rather lead with
+            let pos = e.position();
+            let message = e.to_string();
+            let suffix = format!(" ({})", pos);
+            with_position(pos, message.strip_suffix(&suffix).unwrap_or(&message))
+        }
+    }
+}
+
+/// Line of the script an error points at, following function calls down to where it failed
+/// like describe_eval_error() does
+pub fn error_line(e: &EvalAltResult) -> Option<usize> {
+    match e {
+        EvalAltResult::ErrorInFunctionCall(_, _, inner, _) => error_line(inner),
+        _ => e.position().line(),
+    }
+}
+
+fn with_position(pos: Position, message: impl Display) -> String {
+    match (pos.line(), pos.position()) {
+        (Some(line), Some(col)) => format!("line {}, col {}: {}", line, col, message),
+        (Some(line), None) => format!("line {}: {}", line, message),
+        _ => message.to_string(),
+    }
+}
+
+/// The key in state.transforms of an entity id passed to a native function, which may be given
+/// as that string or as the same number as an integer
+fn entity_key(id: &Dynamic) -> Result<String, Box<EvalAltResult>> {
+    if id.is_int() || id.is_string() {
+        Ok(id.to_string())
+    } else {
+        Err(script_error(
+            "TypeError",
+            format!(
+                "Expected an entity id as a string or integer, found {}",
+                id.type_name()
+            ),
+        ))
+    }
+}
+
+/// Parse an entity id from the string keys used in state.transforms
+fn parse_entity_id(key: &str) -> Result<EntityId, Box<EvalAltResult>> {
+    key.parse()
+        .map(EntityId)
+        .map_err(|_| script_error("EntityError", format!("Invalid entity id \"{}\"", key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    /// A component from outside the crate, as far as scripts are concerned
+    #[derive(Component, Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+    struct Counter {
+        count: INT,
+    }
+
+    impl CheckComponent for Counter {
+        fn check(&self) -> Result<(), String> {
+            match self.count {
+                0.. => Ok(()),
+                _ => Err("Negative count".into()),
+            }
+        }
+    }
+
+    /// Components by entity, standing in for the query
+    #[derive(Default)]
+    struct Store(HashMap<EntityId, Dynamic>);
+
+    impl ComponentStore for Store {
+        fn read<C: Component + Clone + Send + Sync + 'static>(&self, id: EntityId) -> C {
+            self.0[&id].clone().cast::<C>()
+        }
+
+        fn write<C: Component + Clone + Send + Sync + 'static>(&mut self, id: EntityId, c: &C) {
+            self.0.insert(id, Dynamic::from(*c));
+        }
+    }
+
+    #[test]
+    fn scripts_edit_custom_components() {
+        let mut store = Store::default();
+        for (id, count) in [(1, 1), (2, 5)] {
+            store
+                .0
+                .insert(EntityId(id), Dynamic::from(Counter { count }));
+        }
+        let ids = [EntityId(1), EntityId(2)];
+        let mut copies = Copies::new();
+        let to_value = |c: &Counter| rhai::serde::to_dynamic(c).unwrap();
+        let counters = read_components(&mut store, &ids, &mut copies, to_value);
+
+        let runtime = ScriptRuntime::new("");
+        let mut scope = Scope::new();
+        scope.push("counters", counters);
+        let edited: Dynamic = runtime
+            .engine
+            .eval_with_scope(
+                &mut scope,
+                r#"
+                    counters["1"].count += 1;
+                    counters["2"].count = -1;
+                    counters["3"] = #{ count: 0 };
+                    counters
+                "#,
+            )
+            .unwrap();
+
+        let errors = write_components::<Counter, _>(&mut store, "counters", edited, &copies);
+        assert_eq!(store.read::<Counter>(EntityId(1)), Counter { count: 2 });
+        assert_eq!(store.read::<Counter>(EntityId(2)), Counter { count: 5 });
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("Negative count"));
+        assert!(errors[1].contains("unknown entity \"3\""));
+    }
+
+    #[test]
+    fn runaway_commands_hit_the_operation_limit() {
+        let mut runtime = ScriptRuntime::new("");
+        assert!(runtime.run_command("set_operation_limit(1000)").is_ok());
+
+        let e = runtime.run_command("loop {}").unwrap_err();
+        assert!(matches!(*e, EvalAltResult::ErrorTerminated(..)));
+        assert!(describe_eval_error(&e).contains("Script exceeded operation limit"));
+
+        // The count starts over, so short commands still run afterwards
+        assert_eq!(runtime.run_command("1 + 1").unwrap().as_int(), Ok(2));
+    }
+
+    #[test]
+    fn deep_recursion_and_nesting_are_reported() {
+        let mut runtime = ScriptRuntime::new("fn down(n) { down(n + 1) }");
+
+        let e = runtime.run_command("down(0)").unwrap_err();
+        assert!(describe_eval_error(&e).contains("Recursion too deep"));
+
+        let nested = format!("{}1{}", "(".repeat(200), ")".repeat(200));
+        let e = runtime.run_command(&nested).unwrap_err();
+        assert!(describe_eval_error(&e).contains("Expression nested too deeply"));
+    }
+
+    #[test]
+    fn commands_can_have_several_statements() {
+        let mut runtime = ScriptRuntime::new("");
+        let result = runtime.run_command("let x = 1; x + 1").unwrap();
+        assert_eq!(result.as_int(), Ok(2));
+
+        // Variables declared by one command are kept for the next
+        assert_eq!(runtime.run_command("x").unwrap().as_int(), Ok(1));
+    }
+
+    #[test]
+    fn script_errors_are_categorized() {
+        let mut runtime = ScriptRuntime::new("");
+        let mut category = |command: &str| {
+            let e = runtime.run_command(command).unwrap_err();
+            ScriptError::new(&e, describe_eval_error(&e))
+        };
+
+        assert!(matches!(category("1 +"), ScriptError::Compile(_)));
+        assert!(matches!(
+            category("exists(1.5)"),
+            ScriptError::TypeMismatch(_)
+        ));
+        assert!(matches!(
+            category("throw \"oops\""),
+            ScriptError::Runtime(_)
+        ));
+        assert!(matches!(
+            category("set_operation_limit(100); loop {}"),
+            ScriptError::Timeout(_)
+        ));
+    }
+
+    #[test]
+    fn commands_can_call_script_functions() {
+        let mut runtime = ScriptRuntime::new("fn helper(x) { x * 2 }");
+        assert_eq!(runtime.run_command("helper(21)").unwrap().as_int(), Ok(42));
+
+        // Recompiling replaces them
+        assert!(runtime.compile("fn helper(x) { x * 3 }").is_ok());
+        assert_eq!(runtime.run_command("helper(2)").unwrap().as_int(), Ok(6));
+    }
+
+    #[test]
+    fn push_line_drops_whole_lines_past_capacity() {
+        let mut buffer = String::new();
+        for line in ["one", "two", "three", "four"] {
+            push_line(&mut buffer, line, 12);
+        }
+        assert_eq!(buffer, "three\nfour\n");
+
+        let mut buffer = String::new();
+        push_line(&mut buffer, "ab", 8);
+        push_line(&mut buffer, "cd", 8);
+        assert_eq!(buffer, "ab\ncd\n");
+        push_line(&mut buffer, "é", 8);
+        assert_eq!(buffer, "cd\né\n");
+    }
+
+    #[test]
+    fn state_survives_a_snapshot() {
+        let mut runtime = ScriptRuntime::new("");
+        let command = "state.counter = 3; state.names = [\"a\"]; state.callback = || 1";
+        assert!(runtime.run_command(command).is_ok());
+        let (json, skipped) = runtime.snapshot_state();
+        assert_eq!(skipped, ["callback"]);
+
+        // As if the plugin reloaded
+        let mut runtime = ScriptRuntime::new("");
+        assert_eq!(runtime.restore_state(&json).unwrap(), 2);
+        let restored = runtime.run_command("state.counter + state.names.len()");
+        assert_eq!(restored.unwrap().as_int(), Ok(4));
+
+        assert!(runtime.restore_state("not json").is_err());
+    }
+
+    #[test]
+    fn frame_variables_match_state() {
+        let mut runtime = ScriptRuntime::new("");
+        runtime.set_dt(0.25);
+        let same = runtime.run_command("dt == state.dt && dt == 0.25");
+        assert_eq!(same.unwrap().as_bool(), Ok(true));
+
+        let transforms: rhai::Map = [("1".into(), Dynamic::from(Transform::default()))]
+            .into_iter()
+            .collect();
+        runtime.frame_vars.lock().unwrap().insert(
+            "transforms".into(),
+            Dynamic::from(transforms).into_read_only().into_shared(),
+        );
+        let x = runtime.run_command("transforms[\"1\"].pos.x");
+        assert_eq!(x.unwrap().as_float(), Ok(0.0));
+        assert!(runtime
+            .run_command("transforms[\"1\"].pos.x = 2.0")
+            .is_err());
+
+        // Declared variables take precedence
+        let shadowed = runtime.run_command("let dt = 1; dt");
+        assert_eq!(shadowed.unwrap().as_int(), Ok(1));
+    }
+
+    #[test]
+    fn jobs_run_once_a_frame_until_done() {
+        let mut runtime = ScriptRuntime::new("");
+        let command = "let n = 3; start_job(|| { n -= 1; n == 0 })";
+        assert!(runtime.run_command(command).is_ok());
+        assert_eq!(runtime.job_count(), 1);
+
+        for remaining in [2, 1] {
+            assert!(runtime.run_jobs().is_empty());
+            assert_eq!(runtime.job_count(), 1);
+            assert_eq!(runtime.run_command("n").unwrap().as_int(), Ok(remaining));
+        }
+        assert!(runtime.run_jobs().is_empty());
+        assert_eq!(runtime.job_count(), 0);
+
+        // Finished jobs aren't called again
+        assert!(runtime.run_jobs().is_empty());
+        assert_eq!(runtime.run_command("n").unwrap().as_int(), Ok(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Transform must be the first component")]
+    fn transform_must_come_first() {
+        let mut components = script_components();
+        components.reverse();
+        ScriptRuntime::with_components("", components);
+    }
+
+    #[test]
+    fn math_prelude_is_available() {
+        let mut runtime = ScriptRuntime::new("");
+        for check in [
+            "sin(0.0) == 0.0",
+            "cos(0.0) == 1.0",
+            "tan(0.0) == 0.0",
+            "atan2(0.0, 1.0) == 0.0",
+            "sqrt(4.0) == 2.0",
+            "abs(-2) == 2 && abs(-2.0) == 2.0",
+            "clamp(5, 0, 3) == 3 && clamp(-0.5, 0.0, 1.0) == 0.0",
+            "min(1, 2) == 1 && max(1.0, 2.0) == 2.0",
+            "floor(1.5) == 1.0 && ceil(1.5) == 2.0 && round(1.4) == 1.0",
+        ] {
+            let result = runtime.run_command(check).map(|value| value.as_bool());
+            assert!(matches!(result, Ok(Ok(true))), "{}", check);
+        }
+    }
+
+    #[test]
+    fn native_errors_can_be_caught() {
+        let mut runtime = ScriptRuntime::new("");
+        let command = r#"
+            let caught = [];
+            for f in [|| set_operation_limit(0), || after_frames(-1, "1"), || get_transform(1.5), || get_transform(7)] {
+                try { f.call(); } catch (e) { caught.push(`${e.error}: ${e.message}`); }
+            }
+            caught
+        "#;
+        let caught = runtime
+            .run_command(command)
+            .unwrap()
+            .into_typed_array::<String>();
+        assert_eq!(
+            caught.unwrap(),
+            [
+                "ArgumentError: Operation limit must be positive",
+                "ArgumentError: after_frames() needs zero or more frames",
+                "TypeError: Expected an entity id as a string or integer, found f32",
+                "EntityError: No entity \"7\" in state.transforms",
+            ]
+        );
+
+        // Uncaught, the kind and message are reported
+        let e = runtime.run_command("set_operation_limit(-1)").unwrap_err();
+        assert!(describe_eval_error(&e).contains("ArgumentError: Operation limit must be positive"));
+    }
+
+    #[test]
+    fn lint_warns_about_likely_mistakes() {
+        let lint = |script: &str| ScriptRuntime::new(script).lint();
+
+        for empty in ["", "// Just a comment"] {
+            let warnings = lint(empty);
+            assert_eq!(warnings.len(), 1);
+            assert!(warnings[0].contains("The script is empty"));
+        }
+        let warnings = lint("fn init() { this.x = 0; }");
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("No update() function"));
+        // update() taking an argument isn't called each frame
+        assert_eq!(lint("fn update(x) {}").len(), 1);
+
+        assert!(lint("fn update() { state.x += 1; }").is_empty());
+        assert!(lint("fn update_entity(t) { t }").is_empty());
+        assert!(lint(include_str!("default.rhai")).is_empty());
+    }
+
+    #[test]
+    fn init_runs_once_per_compile() {
+        let script = "fn init() { this.inits = (this.inits ?? 0) + 1; } fn update() {}";
+        let mut runtime = ScriptRuntime::new(script);
+        let inits = |runtime: &mut ScriptRuntime| runtime.run_command("state.inits").unwrap();
+
+        for _ in 0..3 {
+            assert!(runtime.run_init().is_ok());
+        }
+        assert_eq!(inits(&mut runtime).as_int(), Ok(1));
+
+        assert!(runtime.compile(script).is_ok());
+        assert!(runtime.run_init().is_ok());
+        assert!(runtime.run_init().is_ok());
+        assert_eq!(inits(&mut runtime).as_int(), Ok(2));
+
+        // A script which doesn't parse leaves the last one running, without calling init() again
+        assert!(runtime.compile("fn init() {").is_err());
+        assert!(runtime.run_init().is_ok());
+        assert_eq!(inits(&mut runtime).as_int(), Ok(2));
+    }
+
+    #[test]
+    fn entities_can_be_tagged() {
+        let mut runtime = ScriptRuntime::new("");
+        let mut check = |command: &str| {
+            let result = runtime.run_command(command).map(|value| value.as_bool());
+            assert!(matches!(result, Ok(Ok(true))), "{}", command);
+        };
+
+        // Ids can be strings or integers, and tagging twice changes nothing
+        check(r#"tag(3, "enemies"); tag("1", "enemies"); tag(1, "enemies"); tag(1, "boss"); true"#);
+        check(r#"entities_in("enemies") == ["1", "3"] && entities_in("boss") == ["1"]"#);
+        check(r#"has_tag("1", "enemies") && has_tag(3, "enemies") && !has_tag(3, "boss")"#);
+        check(r#"entities_in("friends") == [] && !has_tag(1, "friends")"#);
+
+        check(r#"untag(1, "enemies"); untag(1, "friends"); true"#);
+        check(r#"entities_in("enemies") == ["3"] && has_tag(1, "boss")"#);
+        check(r#"untag(1, "boss"); entities_in("boss") == []"#);
+
+        assert!(runtime.run_command(r#"tag("player", "enemies")"#).is_err());
+        assert!(runtime.run_command(r#"has_tag(1.5, "enemies")"#).is_err());
+    }
+
+    #[test]
+    fn deferred_commands_run_once_when_due() {
+        let mut runtime = ScriptRuntime::new("");
+        let command = r#"after_frames(2, "frames"); after_frames(0, "now"); after(1.5, "time")"#;
+        assert!(runtime.run_command(command).is_ok());
+        // As begin_frame() would advance them
+        let next_frame = |runtime: &ScriptRuntime, elapsed: FLOAT| {
+            runtime.frame_count.fetch_add(1, Ordering::Relaxed);
+            *runtime.elapsed.lock().unwrap() = elapsed;
+            runtime.take_due_commands()
+        };
+
+        assert_eq!(runtime.take_due_commands(), ["now"]);
+        assert!(runtime.take_due_commands().is_empty());
+        assert!(next_frame(&runtime, 0.5).is_empty());
+        assert_eq!(next_frame(&runtime, 1.0), ["frames"]);
+        assert_eq!(next_frame(&runtime, 1.5), ["time"]);
+        assert!(next_frame(&runtime, 2.0).is_empty());
+
+        // Recompiling cancels anything still waiting
+        assert!(runtime.run_command(r#"after(1.0, "late")"#).is_ok());
+        assert!(runtime.compile("").is_ok());
+        assert!(next_frame(&runtime, 5.0).is_empty());
+    }
+
+    #[test]
+    fn swapped_scripts_keep_their_own_state() {
+        let mut runtime = ScriptRuntime::new("fn helper() { 1 }");
+        let command = r#"state.x = 1; let y = 2; bind_key("J", "1"); after_frames(5, "1"); start_job(|| false)"#;
+        assert!(runtime.run_command(command).is_ok());
+
+        let mut parked = ParkedScript::default();
+        runtime.swap_script(&mut parked);
+        assert_eq!(runtime.script, "");
+        assert_eq!(parked.script, "fn helper() { 1 }");
+        let fresh = runtime.run_command("state.x == () && !is_def_var(\"y\")");
+        assert_eq!(fresh.unwrap().as_bool(), Ok(true));
+        assert!(runtime.run_command("helper()").is_err());
+        assert_eq!(runtime.key_binding("J"), None);
+        assert_eq!(runtime.job_count(), 0);
+        runtime.frame_count.store(10, Ordering::Relaxed);
+        assert!(runtime.take_due_commands().is_empty());
+        assert!(runtime.run_command("state.x = 3").is_ok());
+
+        runtime.swap_script(&mut parked);
+        let restored = runtime.run_command("state.x + y + helper()");
+        assert_eq!(restored.unwrap().as_int(), Ok(4));
+        assert_eq!(runtime.key_binding("J").as_deref(), Some("1"));
+        assert_eq!(runtime.job_count(), 1);
+        assert_eq!(runtime.take_due_commands(), ["1"]);
+
+        // The other script's state went with it
+        runtime.swap_script(&mut parked);
+        assert_eq!(runtime.run_command("state.x").unwrap().as_int(), Ok(3));
+    }
+
+    #[test]
+    fn dry_runs_leave_the_ecs_alone() {
+        let mut runtime = ScriptRuntime::new("");
+        runtime.requests.lock().unwrap().reserved.push(EntityId(42));
+        let command = r#"
+            let id = spawn_entity(Transform());
+            delete_entity(1);
+            remove_render(2);
+            message("ping", #{});
+            share("score", 1);
+            bind_key("J", "1");
+            tag(1, "enemies");
+            after_frames(0, "1");
+            start_job(|| false);
+            state.note = "kept";
+            id
+        "#;
+        assert_eq!(
+            runtime
+                .dry_run_command(command)
+                .unwrap()
+                .into_string()
+                .unwrap(),
+            "42"
+        );
+
+        let requests = runtime.requests.lock().unwrap();
+        assert_eq!(requests.reserved, [EntityId(42)]);
+        assert!(requests.spawns.is_empty());
+        assert!(requests.render_changes.is_empty());
+        assert!(requests.deletes.is_empty());
+        drop(requests);
+        assert!(runtime.outbox.lock().unwrap().is_empty());
+        assert!(runtime.take_shared_writes().is_empty());
+        assert_eq!(runtime.key_binding("J"), None);
+        assert!(runtime.take_due_commands().is_empty());
+        assert_eq!(runtime.job_count(), 0);
+        let kept = runtime
+            .run_command(r#"entities_in("enemies") == [] && state.note == "kept" && id == "42""#);
+        assert_eq!(kept.unwrap().as_bool(), Ok(true));
+
+        // The same command run for real queues the spawn
+        assert!(runtime.run_command("spawn_entity(Transform())").is_ok());
+        assert_eq!(runtime.requests.lock().unwrap().spawns.len(), 1);
+    }
+
+    #[test]
+    fn failed_compiles_keep_the_previous_script() {
+        let script = "fn update() { this.frames = (this.frames ?? 0) + 1; }";
+        let mut runtime = ScriptRuntime::new(script);
+        assert!(runtime.run_update().is_ok());
+
+        let result = runtime.compile("fn update() { this.frames = ");
+        assert!(matches!(result, Err(CompileError::Parse(_))));
+        assert_eq!(runtime.script, script);
+        assert!(runtime.run_update().is_ok());
+        assert_eq!(runtime.run_command("state.frames").unwrap().as_int(), Ok(2));
+
+        // Errors running the top-level statements still replace it
+        let failing = "fn update() {} throw \"oops\";";
+        assert!(matches!(
+            runtime.compile(failing),
+            Err(CompileError::Run(_))
+        ));
+        assert_eq!(runtime.script, failing);
+    }
+}
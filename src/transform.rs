@@ -0,0 +1,339 @@
+//! `Transform` as a native Rhai type.
+//!
+//! Values in `state.transforms` are real `Transform`s rather than serialized maps, so a typo
+//! like `t.poss` is an error instead of silently adding a new key. `pos` and `orient` still read
+//! and write the same arrays the math helpers use, and `t["pos"]` keeps working for older scripts.
+use std::collections::BTreeMap;
+
+use cimvr_common::{
+    glam::{Mat4, Quat, Vec3},
+    Transform,
+};
+use rhai::{format_map_as_json, Array, Dynamic, Engine, EvalAltResult, Map, NativeCallContext};
+
+use crate::math::{from_quat, from_vec3, to_quat, to_vec3};
+use crate::runtime::{component_from_dynamic, script_error};
+
+pub fn register(engine: &mut Engine) {
+    engine.register_type_with_name::<Transform>("Transform");
+
+    engine.register_fn("Transform", Transform::default);
+    // At the origin, facing the default direction, e.g. `state.transforms[id] = identity()`
+    engine.register_fn("identity", Transform::default);
+    engine.register_fn("is_identity", |t: Transform| t == Transform::default());
+    engine.register_fn(
+        "transform",
+        |pos: Array, orient: Array| -> Result<Transform, Box<EvalAltResult>> {
+            Ok(Transform {
+                pos: to_vec3(&pos)?,
+                orient: to_quat(&orient)?,
+            })
+        },
+    );
+
+    engine.register_get_set("pos", get_pos, set_pos);
+    engine.register_get_set("orient", get_orient, set_orient);
+
+    engine.register_indexer_get(
+        |t: &mut Transform, field: &str| -> Result<Array, Box<EvalAltResult>> {
+            match field {
+                "pos" => Ok(get_pos(t)),
+                "orient" => Ok(get_orient(t)),
+                _ => Err(unknown_field(field)),
+            }
+        },
+    );
+    engine.register_indexer_set(
+        |t: &mut Transform, field: &str, value: Array| -> Result<(), Box<EvalAltResult>> {
+            match field {
+                "pos" => set_pos(t, value),
+                "orient" => set_orient(t, value),
+                _ => Err(unknown_field(field)),
+            }
+        },
+    );
+
+    // Orientation which makes `t` face `target`, with -Z as forward and +Y as up, matching
+    // cimvr's right-handed coordinates. A target at `t`'s own position throws an ArgumentError,
+    // and looking straight along `up` picks the shortest turn from -Z, leaving the roll arbitrary.
+    engine.register_fn(
+        "look_at",
+        |t: Transform, target: Array| -> Result<Array, Box<EvalAltResult>> {
+            look_at(t.pos, to_vec3(&target)?, Vec3::Y)
+        },
+    );
+    engine.register_fn(
+        "look_at",
+        |t: Transform, target: Array, up: Array| -> Result<Array, Box<EvalAltResult>> {
+            look_at(t.pos, to_vec3(&target)?, to_vec3(&up)?)
+        },
+    );
+
+    // World transform of a child placed at `local` relative to `parent`, for keeping parts of
+    // an object together: `state.transforms[arm] = compose(state.transforms[body], offset)`.
+    // Gives the same result as `mat4_to_transform(mat4_mul(mat4(parent), mat4(local)))`.
+    engine.register_fn("compose", |parent: Transform, local: Transform| Transform {
+        pos: parent.pos + parent.orient * local.pos,
+        orient: (parent.orient * local.orient).normalize(),
+    });
+
+    // Transforms by id, such as state.transforms, as JSON for exporting a scene. Each one is
+    // written as `{"pos": [x, y, z], "orient": [x, y, z, w]}`.
+    engine.register_fn(
+        "transforms_to_json",
+        |transforms: Map| -> Result<String, Box<EvalAltResult>> {
+            let mut json = Map::new();
+            for (id, value) in transforms {
+                let mut t: Transform = component_from_dynamic(value).map_err(|_| {
+                    script_error("TypeError", format!("Entry \"{}\" isn't a transform", id))
+                })?;
+                let mut fields = Map::new();
+                fields.insert("pos".into(), get_pos(&mut t).into());
+                fields.insert("orient".into(), get_orient(&mut t).into());
+                json.insert(id, fields.into());
+            }
+            Ok(format_map_as_json(&json))
+        },
+    );
+
+    // The inverse of transforms_to_json(). Ids are kept as they were, so entries only match
+    // entities in this scene if they were exported from it.
+    engine.register_fn(
+        "transforms_from_json",
+        |context: NativeCallContext, json: &str| -> Result<Map, Box<EvalAltResult>> {
+            Ok(parse_transforms(context.engine(), json)?
+                .into_iter()
+                .map(|(id, t)| (id.into(), Dynamic::from(t)))
+                .collect())
+        },
+    );
+
+    engine.register_fn("to_string", |t: &mut Transform| {
+        format!("Transform(pos: {:?}, orient: {:?})", t.pos, t.orient)
+    });
+    engine.register_fn("to_debug", |t: &mut Transform| format!("{:?}", t));
+}
+
+/// Read transforms by id from JSON written by transforms_to_json()
+pub fn parse_transforms(
+    engine: &Engine,
+    json: &str,
+) -> Result<BTreeMap<String, Transform>, Box<EvalAltResult>> {
+    let map = engine
+        .parse_json(json, false)
+        .map_err(|e| script_error("ArgumentError", format!("Invalid scene JSON: {}", e)))?;
+
+    let mut transforms = BTreeMap::new();
+    for (id, value) in map {
+        let t: Transform = component_from_dynamic(value).map_err(|e| {
+            script_error(
+                "ArgumentError",
+                format!("Entry \"{}\" isn't a transform: {}", id, e),
+            )
+        })?;
+        transforms.insert(id.to_string(), t);
+    }
+    Ok(transforms)
+}
+
+fn get_pos(t: &mut Transform) -> Array {
+    from_vec3(t.pos)
+}
+
+fn set_pos(t: &mut Transform, pos: Array) -> Result<(), Box<EvalAltResult>> {
+    t.pos = to_vec3(&pos)?;
+    Ok(())
+}
+
+fn get_orient(t: &mut Transform) -> Array {
+    from_quat(t.orient)
+}
+
+fn set_orient(t: &mut Transform, orient: Array) -> Result<(), Box<EvalAltResult>> {
+    t.orient = to_quat(&orient)?;
+    Ok(())
+}
+
+fn look_at(pos: Vec3, target: Vec3, up: Vec3) -> Result<Array, Box<EvalAltResult>> {
+    let forward = (target - pos).try_normalize().ok_or_else(|| {
+        script_error(
+            "ArgumentError",
+            "look_at() target is at the entity's position",
+        )
+    })?;
+
+    // The up vector only decides the roll, so any will do when looking straight along it
+    let orient = if forward.cross(up).length_squared() > 1e-6 {
+        // look_at_rh() is a view matrix, taking world space to the entity's space
+        Quat::from_mat4(&Mat4::look_at_rh(pos, target, up).inverse())
+    } else {
+        Quat::from_rotation_arc(Vec3::NEG_Z, forward)
+    };
+    Ok(from_quat(orient.normalize()))
+}
+
+fn unknown_field(field: &str) -> Box<EvalAltResult> {
+    script_error(
+        "FieldError",
+        format!(
+            "Transform has no field \"{}\"; expected \"pos\" or \"orient\"",
+            field
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine() -> Engine {
+        let mut engine = Engine::new();
+        crate::math::register(&mut engine);
+        register(&mut engine);
+        engine
+    }
+
+    fn eval_quat(engine: &Engine, script: &str) -> Quat {
+        to_quat(&engine.eval::<Array>(script).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn look_at_faces_the_target() {
+        let engine = engine();
+
+        // Forward is -Z, so looking down -Z needs no rotation
+        let ahead = eval_quat(&engine, "look_at(identity(), [0, 0, -5])");
+        assert!(ahead.abs_diff_eq(Quat::IDENTITY, 1e-6));
+
+        let right = eval_quat(
+            &engine,
+            "look_at(transform([1, 2, 3], [0, 0, 0, 1]), [4, 2, 3])",
+        );
+        assert!((right * Vec3::NEG_Z).abs_diff_eq(Vec3::X, 1e-6));
+        assert!((right * Vec3::Y).abs_diff_eq(Vec3::Y, 1e-6));
+
+        let rolled = eval_quat(&engine, "look_at(identity(), [0, 0, -1], [1, 0, 0])");
+        assert!((rolled * Vec3::NEG_Z).abs_diff_eq(Vec3::NEG_Z, 1e-6));
+        assert!((rolled * Vec3::Y).abs_diff_eq(Vec3::X, 1e-6));
+    }
+
+    #[test]
+    fn look_at_degenerate_cases() {
+        let engine = engine();
+
+        let caught = engine
+            .eval::<String>(
+                "let caught = \"\"; try { look_at(identity(), [0, 0, 0]); } catch (e) { caught = e.error; } caught",
+            )
+            .unwrap();
+        assert_eq!(caught, "ArgumentError");
+
+        for (target, forward) in [("[0, 5, 0]", Vec3::Y), ("[0, -5, 0]", Vec3::NEG_Y)] {
+            let q = eval_quat(&engine, &format!("look_at(identity(), {})", target));
+            assert!(q.is_finite() && q.is_normalized());
+            assert!((q * Vec3::NEG_Z).abs_diff_eq(forward, 1e-6));
+        }
+        // Directly behind is also exactly opposite the default forward
+        let behind = eval_quat(&engine, "look_at(identity(), [0, 0, 5], [0, 0, 1])");
+        assert!((behind * Vec3::NEG_Z).abs_diff_eq(Vec3::Z, 1e-6));
+    }
+
+    #[test]
+    fn compose_matches_the_matrix_product() {
+        let engine = engine();
+        let script = |expr: &str| {
+            engine
+                .eval::<Transform>(&format!(
+                    "let parent = transform([1, 2, 3], quat_from_axis_angle([0, 1, 0], 1.));
+                    let local = transform([0, 0, -2], quat_from_axis_angle([1, 0, 0], 0.5));
+                    {}",
+                    expr
+                ))
+                .unwrap()
+        };
+
+        let composed = script("compose(parent, local)");
+        let multiplied = script("mat4_to_transform(mat4_mul(mat4(parent), mat4(local)))");
+        assert!(composed.pos.abs_diff_eq(multiplied.pos, 1e-5));
+        assert!(composed.orient.abs_diff_eq(multiplied.orient, 1e-5));
+
+        // The identity on either side changes nothing
+        let parent = script("parent");
+        let left = script("compose(identity(), parent)");
+        let right = script("compose(parent, identity())");
+        assert!(left.pos.abs_diff_eq(parent.pos, 1e-6) && right.pos.abs_diff_eq(parent.pos, 1e-6));
+        assert!(left.orient.abs_diff_eq(parent.orient, 1e-6));
+        assert!(right.orient.abs_diff_eq(parent.orient, 1e-6));
+    }
+
+    #[test]
+    fn transforms_round_trip_through_json() {
+        let engine = engine();
+        let json = engine
+            .eval::<String>(
+                "transforms_to_json(#{
+                    a: transform([1, 2, 3], quat_from_axis_angle([0, 1, 0], 1.)),
+                    b: identity(),
+                })",
+            )
+            .unwrap();
+
+        let transforms = parse_transforms(&engine, &json).unwrap();
+        assert_eq!(transforms.len(), 2);
+        assert_eq!(transforms["a"].pos, Vec3::new(1.0, 2.0, 3.0));
+        assert!(transforms["a"]
+            .orient
+            .abs_diff_eq(Quat::from_rotation_y(1.0), 1e-6));
+        assert_eq!(transforms["b"], Transform::default());
+
+        let same = engine
+            .eval::<bool>(&format!(
+                "let t = transforms_from_json({:?}); t.b.is_identity() && t.a.pos == [1.0, 2.0, 3.0]",
+                json
+            ))
+            .unwrap();
+        assert!(same);
+        assert!(engine
+            .eval::<String>("transforms_to_json(#{ a: 1 })")
+            .is_err());
+    }
+
+    #[test]
+    fn malformed_scene_json_is_an_error() {
+        let engine = engine();
+        for json in [
+            "",
+            "{",
+            "[1, 2, 3]",
+            r#"{"a": 5}"#,
+            r#"{"a": {"pos": [1, 2], "orient": [0, 0, 0, 1]}}"#,
+            r#"{"a": {"pos": [1, 2, 3]}}"#,
+        ] {
+            assert!(parse_transforms(&engine, json).is_err(), "{:?}", json);
+        }
+
+        let caught = engine
+            .eval::<String>(
+                "let caught = \"\"; try { transforms_from_json(\"{\"); } catch (e) { caught = e.error; } caught",
+            )
+            .unwrap();
+        assert_eq!(caught, "ArgumentError");
+    }
+
+    #[test]
+    fn identity_transform() {
+        let engine = engine();
+        assert_eq!(
+            engine.eval::<Transform>("identity()").unwrap(),
+            Transform::default()
+        );
+
+        let check = |script: &str| engine.eval::<bool>(script).unwrap();
+        assert!(check("identity().is_identity()"));
+        assert!(check("transform([0, 0, 0], [0, 0, 0, 1]).is_identity()"));
+        assert!(!check("transform([0, 0.1, 0], [0, 0, 0, 1]).is_identity()"));
+        assert!(!check(
+            "let t = identity(); t.orient = quat_from_axis_angle([0, 1, 0], 1.); t.is_identity()"
+        ));
+    }
+}
@@ -0,0 +1,86 @@
+//! Snapshots of the scene's transforms, restored by the Undo button
+
+use std::collections::{HashMap, VecDeque};
+
+use cimvr_common::Transform;
+use cimvr_engine_interface::prelude::*;
+
+/// Maximum number of snapshots kept. Older ones are dropped, so undo can't go back further.
+const UNDO_CAPACITY: usize = 32;
+
+pub type Snapshot = HashMap<EntityId, Transform>;
+
+#[derive(Default)]
+pub struct TransformUndo {
+    snapshots: VecDeque<Snapshot>,
+    /// Transforms as of the last call to record()
+    last: Snapshot,
+}
+
+impl TransformUndo {
+    /// Call once a frame with the current transforms. If they changed since the last call, the
+    /// old ones are kept as a snapshot to undo to.
+    pub fn record(&mut self, current: Snapshot) {
+        if current == self.last {
+            return;
+        }
+        let previous = std::mem::replace(&mut self.last, current);
+        // Nothing to go back to before the first entities appear
+        if previous.is_empty() {
+            return;
+        }
+        if self.snapshots.len() == UNDO_CAPACITY {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(previous);
+    }
+
+    /// Take the most recent snapshot, or None if there's nothing left to undo. Restoring it
+    /// doesn't count as an edit, so repeated undos keep stepping further back.
+    pub fn undo(&mut self) -> Option<Snapshot> {
+        let snapshot = self.snapshots.pop_back()?;
+        self.last = snapshot.clone();
+        Some(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cimvr_common::glam::{Quat, Vec3};
+
+    /// One entity, at `x` along the X axis
+    fn at(x: f32) -> Snapshot {
+        let transform = Transform {
+            pos: Vec3::new(x, 0., 0.),
+            orient: Quat::IDENTITY,
+        };
+        [(EntityId(1), transform)].into_iter().collect()
+    }
+
+    #[test]
+    fn undo_steps_back_through_edits() {
+        let mut undo = TransformUndo::default();
+        for x in [0., 1., 1., 2.] {
+            undo.record(at(x));
+        }
+        assert_eq!(undo.undo(), Some(at(1.)));
+        // Recording the restored transforms isn't a new edit
+        undo.record(at(1.));
+        assert_eq!(undo.undo(), Some(at(0.)));
+        assert_eq!(undo.undo(), None);
+    }
+
+    #[test]
+    fn oldest_snapshots_are_dropped() {
+        let mut undo = TransformUndo::default();
+        for x in 0..=UNDO_CAPACITY + 1 {
+            undo.record(at(x as f32));
+        }
+        let mut oldest = None;
+        while let Some(snapshot) = undo.undo() {
+            oldest = Some(snapshot);
+        }
+        assert_eq!(oldest, Some(at(1.)));
+    }
+}